@@ -1,19 +1,137 @@
 /**
  * Reference: https://help.aliyun.com/zh/machine-translation/developer-reference/signature-mechanism
  */
+use async_trait::async_trait;
 use md5::{Md5, Digest};
 use base64::prelude::*;
+use serde::{Deserialize, Deserializer};
+use serde_derive::{Deserialize, Serialize};
 use sha1::Sha1;
 use hmac::{Hmac, Mac};
 use url::Url;
 use uuid::Uuid;
 
+use crate::interpreter::{Language, TranslateError, TranslationBackend};
+use crate::interpreter::backend::http_client;
+
+/// Let the service detect the source language
+const SOURCE_LANGUAGE_AUTO: &str = "auto";
+
 const SIGNATURE_METHOD: &str = "HMAC-SHA1";
 const SIGNATURE_VERSION: &str = "2019-01-02";
 
+/// The success code of the translation API
+const CODE_SUCCESS: &str = "200";
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct TranslateRequest<'a> {
+    format_type: &'a str,
+    source_language: &'a str,
+    target_language: &'a str,
+    source_text: &'a str,
+    scene: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TranslateResponse {
+    #[serde(deserialize_with = "string_or_number")]
+    code: String,
+    message: Option<String>,
+    data: Option<TranslateData>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TranslateData {
+    translated: Option<String>,
+}
+
+/// The `Code` field is documented as a string, but accept numbers as well
+fn string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(s) => Ok(s),
+        serde_json::Value::Number(n) => Ok(n.to_string()),
+        other => Err(serde::de::Error::custom(format!("invalid code: {}", other))),
+    }
+}
+
+/// The translation API to call
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AliyunApi {
+    /// `TranslateECommerce`, which also serves the `social` scene
+    Ecommerce,
+    /// `TranslateGeneral`
+    General,
+}
+
+impl AliyunApi {
+    fn path(&self) -> &'static str {
+        match self {
+            AliyunApi::Ecommerce => "/api/translate/web/ecommerce",
+            AliyunApi::General => "/api/translate/web/general",
+        }
+    }
+
+    fn default_scene(&self) -> &'static str {
+        match self {
+            AliyunApi::Ecommerce => "social",
+            AliyunApi::General => "general",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AliyunConfig {
+    /// Region of the service, e.g. `cn-hangzhou`
+    pub region: String,
+    pub api: AliyunApi,
+    /// Scheme and host to send the requests to instead of the regional
+    /// endpoint, e.g. `http://127.0.0.1:8080` for a mock server
+    pub endpoint: Option<String>,
+    /// Defaults to the scene of the api
+    pub scene: Option<String>,
+    /// `text` or `html`
+    pub format_type: String,
+    /// Whether the health check translates a word, billed as any translation,
+    /// instead of only checking the configuration
+    pub check_translation: bool,
+}
+
+impl Default for AliyunConfig {
+    fn default() -> Self {
+        AliyunConfig {
+            region: "cn-hangzhou".to_string(),
+            api: AliyunApi::Ecommerce,
+            endpoint: None,
+            scene: None,
+            format_type: "text".to_string(),
+            check_translation: false,
+        }
+    }
+}
+
+impl AliyunConfig {
+    fn url(&self) -> String {
+        let endpoint = match &self.endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("http://mt.{}.aliyuncs.com", self.region),
+        };
+        format!("{}{}", endpoint, self.api.path())
+    }
+
+    fn scene(&self) -> &str {
+        self.scene.as_deref().unwrap_or(self.api.default_scene())
+    }
+}
+
 pub struct AliyunCli {
     access_key_id: String,
     access_key_secret: String,
+    config: AliyunConfig,
+    /// Shared by the requests, keeping the connections alive
+    client: reqwest::Client,
 }
 
 impl AliyunCli {
@@ -21,9 +139,16 @@ impl AliyunCli {
         AliyunCli {
             access_key_id,
             access_key_secret,
+            config: AliyunConfig::default(),
+            client: http_client(),
         }
     }
 
+    pub fn with_config(mut self, config: AliyunConfig) -> AliyunCli {
+        self.config = config;
+        self
+    }
+
     /// Calculate the MD5 hash of a string and encode it in base64
     fn md5_base64(s: &str) -> String {
         // Create a new hasher to calculate the MD5 hash
@@ -49,6 +174,68 @@ impl AliyunCli {
         BASE64_STANDARD.encode(&result)
     }
 
+    /// Build the json body of a translation request
+    fn translate_body(
+        &self,
+        text: &str,
+        source: Option<&Language>,
+        target: &Language,
+    ) -> Result<String, TranslateError> {
+        let source = source.map_or(SOURCE_LANGUAGE_AUTO.to_string(), Self::language_code);
+        let target = Self::language_code(target);
+        let request = TranslateRequest {
+            format_type: &self.config.format_type,
+            source_language: &source,
+            target_language: &target,
+            source_text: text,
+            scene: self.config.scene(),
+        };
+        Ok(serde_json::to_string(&request)?)
+    }
+
+    /// The language codes of the service are lowercase, e.g. `zh-tw`
+    fn language_code(language: &Language) -> String {
+        language.code().to_lowercase()
+    }
+
+    /// Extract the translated text from the response body
+    fn parse_translated(body: &str) -> Result<String, TranslateError> {
+        let response: TranslateResponse = serde_json::from_str(body)
+            .map_err(|e| TranslateError::MalformedResponse(format!("{} ({})", e, body)))?;
+
+        if response.code != CODE_SUCCESS {
+            let message = format!(
+                "code {}: {}",
+                response.code,
+                response.message.unwrap_or_default(),
+            );
+            return Err(Self::classify_error(&response.code, message));
+        }
+
+        match response.data.and_then(|data| data.translated) {
+            Some(translated) => Ok(translated),
+            None => Err(TranslateError::MalformedResponse(format!("No translated text: {}", body))),
+        }
+    }
+
+    /// Map an error code to the kind of error
+    /// Reference: https://help.aliyun.com/zh/machine-translation/developer-reference/error-codes
+    fn classify_error(code: &str, message: String) -> TranslateError {
+        match code {
+            // Timeout, system error, service call failed, unknown error
+            "10001" | "10002" | "10012" | "19999" => TranslateError::Service(message),
+            // No permission for the sub account, service not activated
+            "10009" | "10013" => TranslateError::Auth(message),
+            // Account in arrears
+            "10010" => TranslateError::Quota(message),
+            _ if code.starts_with("Throttling") => TranslateError::RateLimited(message),
+            _ if code.starts_with("InvalidAccessKey")
+                || code.starts_with("SignatureDoesNotMatch")
+                || code.starts_with("Forbidden") => TranslateError::Auth(message),
+            _ => TranslateError::Rejected(message),
+        }
+    }
+
     // Get time with format: "E, dd MMM yyyy HH:mm:ss z"
     fn get_time() -> String {
         chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string()
@@ -59,8 +246,12 @@ impl AliyunCli {
         &self,
         url: String,
         body: String,
-    ) -> String {
-        let url = Url::parse(&url).unwrap();
+    ) -> Result<String, TranslateError> {
+        let url = Url::parse(&url)
+            .map_err(|e| TranslateError::Config(format!("Invalid url {}: {}", url, e)))?;
+        let host = url.host_str()
+            .ok_or_else(|| TranslateError::Config(format!("No host in url {}", url)))?
+            .to_string();
 
         // Set the request parameters
         let method = "POST";
@@ -68,7 +259,6 @@ impl AliyunCli {
         let content_type = "application/json;chrset=utf-8";
         let path = url.path();
         let date = Self::get_time();
-        let host = url.host_str().unwrap();
         let uuid = Uuid::new_v4().to_string();
 
         // Apply md5 and base64 to the body
@@ -93,8 +283,7 @@ impl AliyunCli {
         );
 
         // Connect to the server
-        let client = reqwest::Client::new();
-        let response = client.post(url.clone())
+        let response = self.client.post(url.clone())
             .header("Accept", accept)
             .header("Content-Type", content_type)
             .header("Content-MD5", body_md5)
@@ -106,9 +295,227 @@ impl AliyunCli {
             .header("x-acs-version", SIGNATURE_VERSION)
             .body(body)
             .send()
-            .await
-            .unwrap();
+            .await?;
+        let status = response.status();
+        let body = response.text().await?;
 
-        response.text().await.unwrap()
+        // Error responses carry a json body with the error code, unless
+        // they come from somewhere in between, such as a proxy
+        if !status.is_success() && serde_json::from_str::<serde_json::Value>(&body).is_err() {
+            return Err(TranslateError::from_status(status, body));
+        }
+        Ok(body)
+    }
+}
+
+#[async_trait]
+impl TranslationBackend for AliyunCli {
+    fn name(&self) -> &str {
+        "aliyun"
+    }
+
+    async fn translate(
+        &self,
+        text: &str,
+        source: Option<&Language>,
+        target: &Language,
+    ) -> Result<String, TranslateError> {
+        // Send the post request
+        let response = self.send_post(
+            self.config.url(),
+            self.translate_body(text, source, target)?,
+        ).await?;
+
+        Self::parse_translated(&response)
+    }
+
+    fn supported_languages(&self) -> Vec<Language> {
+        Language::all()
+            .into_iter()
+            .filter(|language| *language != Language::BE)
+            .collect()
+    }
+
+    async fn health_check(&self) -> Result<(), TranslateError> {
+        // Every call of the api is billed, including the ones that fail
+        if !self.config.check_translation {
+            if self.access_key_id.trim().is_empty() || self.access_key_secret.trim().is_empty() {
+                return Err(TranslateError::Config("empty access key".to_string()));
+            }
+            return Ok(());
+        }
+        self.translate("ping", None, &Language::EN).await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::interpreter::backend::mock_endpoint;
+    use super::*;
+
+    #[test]
+    fn config_url_and_scene() {
+        let config = AliyunConfig::default();
+        assert_eq!(config.url(), "http://mt.cn-hangzhou.aliyuncs.com/api/translate/web/ecommerce");
+        assert_eq!(config.scene(), "social");
+
+        let config = AliyunConfig {
+            region: "ap-southeast-1".to_string(),
+            api: AliyunApi::General,
+            ..AliyunConfig::default()
+        };
+        assert_eq!(config.url(), "http://mt.ap-southeast-1.aliyuncs.com/api/translate/web/general");
+        assert_eq!(config.scene(), "general");
+
+        let config = AliyunConfig {
+            endpoint: Some("http://127.0.0.1:8080/".to_string()),
+            scene: Some("communication".to_string()),
+            ..AliyunConfig::default()
+        };
+        assert_eq!(config.url(), "http://127.0.0.1:8080/api/translate/web/ecommerce");
+        assert_eq!(config.scene(), "communication");
+    }
+
+    #[test]
+    fn body_escapes_hostile_text() {
+        let cli = AliyunCli::new("id".to_string(), "secret".to_string());
+        let hostile = [
+            r#"he said "push B""#,
+            r"C:\Games\Korabli\",
+            "line one\nline two\r\n",
+            "tab\there",
+            "\u{0}\u{1b}[31mred",
+            "\"}, \"Scene\": \"general",
+            "\u{2028}\u{2029}",
+            "o7 🚢 гг вп",
+        ];
+
+        for text in hostile {
+            let body = cli.translate_body(text, None, &Language::ZH).unwrap();
+            let parsed: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(parsed["SourceText"], text);
+            assert_eq!(parsed["SourceLanguage"], "auto");
+            assert_eq!(parsed["TargetLanguage"], "zh");
+            assert_eq!(parsed["Scene"], "social");
+        }
+    }
+
+    #[test]
+    fn body_language_codes() {
+        let cli = AliyunCli::new("id".to_string(), "secret".to_string());
+        let body = cli.translate_body("o7", Some(&Language::RU), &Language::ZHTW).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(parsed["SourceLanguage"], "ru");
+        assert_eq!(parsed["TargetLanguage"], "zh-tw");
+    }
+
+    #[test]
+    fn parse_success() {
+        let body = r#"{"RequestId":"A1","Data":{"Translated":"他说 \"推 B\"","WordCount":"12"},"Code":"200","Message":"success"}"#;
+        assert_eq!(AliyunCli::parse_translated(body).unwrap(), "他说 \"推 B\"");
+    }
+
+    #[test]
+    fn parse_numeric_code() {
+        let body = r#"{"Data":{"Translated":"好"},"Code":200}"#;
+        assert_eq!(AliyunCli::parse_translated(body).unwrap(), "好");
+    }
+
+    #[test]
+    fn parse_error_code() {
+        let body = r#"{"RequestId":"A2","Code":"10001","Message":"The request has timed out"}"#;
+        let error = AliyunCli::parse_translated(body).unwrap_err();
+        assert!(matches!(error, TranslateError::Service(_)));
+        assert!(error.is_retryable());
+        let error = error.to_string();
+        assert!(error.contains("10001"));
+        assert!(error.contains("The request has timed out"));
+    }
+
+    #[test]
+    fn parse_error_kind() {
+        let auth = r#"{"Code":"InvalidAccessKeyId.NotFound","Message":"Specified access key is not found."}"#;
+        assert!(matches!(AliyunCli::parse_translated(auth), Err(TranslateError::Auth(_))));
+        let quota = r#"{"Code":"10010","Message":"account has overdue payment"}"#;
+        assert!(matches!(AliyunCli::parse_translated(quota), Err(TranslateError::Quota(_))));
+        let throttled = r#"{"Code":"Throttling.User","Message":"Request was denied due to user flow control."}"#;
+        assert!(matches!(AliyunCli::parse_translated(throttled), Err(TranslateError::RateLimited(_))));
+        let rejected = r#"{"Code":"10005","Message":"language pair not supported"}"#;
+        assert!(matches!(AliyunCli::parse_translated(rejected), Err(TranslateError::Rejected(_))));
+    }
+
+    #[test]
+    fn parse_missing_translation() {
+        assert!(matches!(
+            AliyunCli::parse_translated(r#"{"Code":"200","Data":{}}"#),
+            Err(TranslateError::MalformedResponse(_)),
+        ));
+        assert!(AliyunCli::parse_translated(r#"{"Code":"200"}"#).is_err());
+    }
+
+    #[test]
+    fn parse_malformed() {
+        assert!(matches!(
+            AliyunCli::parse_translated("<html>502 Bad Gateway</html>"),
+            Err(TranslateError::MalformedResponse(_)),
+        ));
+        assert!(AliyunCli::parse_translated("").is_err());
+    }
+
+    #[tokio::test]
+    async fn health_check_not_billed() {
+        // Nothing listens there, a request would fail
+        let endpoint = Some("http://127.0.0.1:9".to_string());
+        let cli = AliyunCli::new("id".to_string(), "secret".to_string())
+            .with_config(AliyunConfig { endpoint: endpoint.clone(), ..AliyunConfig::default() });
+        assert!(cli.health_check().await.is_ok());
+        let cli = AliyunCli::new("id".to_string(), " ".to_string())
+            .with_config(AliyunConfig { endpoint: endpoint.clone(), ..AliyunConfig::default() });
+        assert!(matches!(cli.health_check().await, Err(TranslateError::Config(_))));
+
+        let cli = AliyunCli::new("id".to_string(), "secret".to_string())
+            .with_config(AliyunConfig { endpoint, check_translation: true, ..AliyunConfig::default() });
+        assert!(matches!(cli.health_check().await, Err(TranslateError::Network(_))));
+    }
+
+    #[tokio::test]
+    async fn signed_request_to_mock_endpoint() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let cli = AliyunCli::new("id".to_string(), "secret".to_string())
+            .with_config(AliyunConfig {
+                endpoint: Some(endpoint),
+                ..AliyunConfig::default()
+            });
+
+        let response = r#"{"Code":"200","Data":{"Translated":"好"}}"#;
+        let (translated, (head, body)) = tokio::join!(
+            cli.translate("ok", None, &Language::ZH),
+            mock_endpoint(&listener, "200 OK", response),
+        );
+        assert_eq!(translated.unwrap(), "好");
+
+        // Verify the signature the way the server does
+        let mut lines = head.lines();
+        let path = lines.next().unwrap().split(' ').nth(1).unwrap().to_string();
+        assert_eq!(path, "/api/translate/web/ecommerce");
+        let headers: std::collections::HashMap<String, String> = lines
+            .filter_map(|line| line.split_once(": "))
+            .map(|(k, v)| (k.to_lowercase(), v.to_string()))
+            .collect();
+        assert_eq!(headers["content-md5"], AliyunCli::md5_base64(&body));
+        let string_to_sign = format!(
+            "POST\n{}\n{}\n{}\n{}\nx-acs-signature-method:{}\nx-acs-signature-nonce:{}\nx-acs-version:{}\n{}",
+            headers["accept"],
+            headers["content-md5"],
+            headers["content-type"],
+            headers["date"],
+            headers["x-acs-signature-method"],
+            headers["x-acs-signature-nonce"],
+            headers["x-acs-version"],
+            path,
+        );
+        let signature = AliyunCli::hmacsha1_base64(&string_to_sign, "secret");
+        assert_eq!(headers["authorization"], format!("acs id:{}", signature));
     }
 }
@@ -0,0 +1,81 @@
+use std::time::Duration;
+use async_trait::async_trait;
+
+use crate::interpreter::{Language, TranslateError};
+
+/// Longest time a request to a backend may take, the chat waiting for the translations
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+/// Longest time to connect to a backend
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// HTTP client of the backends, giving up on a server that does not answer
+pub(super) fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()
+        .expect("the HTTP client is built without TLS settings")
+}
+
+/// Serve a single request on a local mock endpoint, answering with the status and the JSON body,
+/// and return the request head and body
+#[cfg(test)]
+pub(super) async fn mock_endpoint(
+    listener: &tokio::net::TcpListener,
+    status: &str,
+    response: &str,
+) -> (String, String) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut buffer).await.unwrap();
+        request.extend_from_slice(&buffer[..n]);
+        let text = String::from_utf8_lossy(&request).to_string();
+        if let Some(end) = text.find("\r\n\r\n") {
+            let length = text[..end].lines()
+                .find_map(|line| line.to_lowercase().strip_prefix("content-length: ").map(|l| l.parse::<usize>().unwrap()))
+                .unwrap_or(0);
+            if request.len() >= end + 4 + length {
+                let head = text[..end].to_string();
+                let body = text[end + 4..].to_string();
+                stream.write_all(format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    response.len(),
+                    response,
+                ).as_bytes()).await.unwrap();
+                return (head, body);
+            }
+        }
+    }
+}
+
+/// A translation service the `Interpreter` can delegate to
+#[async_trait]
+pub trait TranslationBackend: Send + Sync {
+    /// Short name of the backend, used in logs
+    fn name(&self) -> &str;
+
+    /// Translate the text into the target language,
+    /// detecting the source language if it is not given
+    async fn translate(
+        &self,
+        text: &str,
+        source: Option<&Language>,
+        target: &Language,
+    ) -> Result<String, TranslateError>;
+
+    /// Detect the language of the text, `None` if the backend cannot tell
+    async fn detect(&self, _text: &str) -> Result<Option<Language>, TranslateError> {
+        Ok(None)
+    }
+
+    /// Languages the backend is able to translate into
+    fn supported_languages(&self) -> Vec<Language>;
+
+    /// Check whether the backend is reachable and usable
+    async fn health_check(&self) -> Result<(), TranslateError>;
+}
@@ -0,0 +1,209 @@
+use std::{
+    num::NonZeroUsize,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use anyhow::Result;
+use lru::LruCache;
+use redb::{Database, TableDefinition};
+use tracing::{debug, info, warn};
+
+/// Translations on disk, keyed by (backend, source language, target language, source text)
+const TRANSLATIONS: TableDefinition<(&str, &str, &str, &str), &str> = TableDefinition::new("translations");
+
+/// Source language of the key when the backend detects it
+const DETECTED_SOURCE: &str = "auto";
+
+/// Number of lookups between two statistics reports in the log
+const REPORT_INTERVAL: u64 = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    backend: String,
+    source: String,
+    target: String,
+    text: String,
+}
+
+impl CacheKey {
+    fn new(backend: &str, source: Option<&str>, target: &str, text: &str) -> CacheKey {
+        CacheKey {
+            backend: backend.to_string(),
+            source: source.unwrap_or(DETECTED_SOURCE).to_string(),
+            target: target.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    fn as_tuple(&self) -> (&str, &str, &str, &str) {
+        (&self.backend, &self.source, &self.target, &self.text)
+    }
+}
+
+/// Cache of translated messages, consulted before calling the backend.
+///
+/// Lookups go through an in-memory LRU first, then through the optional
+/// on-disk database, which survives restarts.
+pub struct TranslationCache {
+    memory: Mutex<LruCache<CacheKey, String>>,
+    disk: Option<Arc<Database>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl TranslationCache {
+    /// Create a cache holding up to `capacity` translations in memory,
+    /// and persisting them to the database at `path` if given
+    pub fn new(capacity: NonZeroUsize, path: Option<&Path>) -> Result<TranslationCache> {
+        let disk = match path {
+            Some(path) => {
+                let database = Database::create(path)?;
+                // Make sure the table exists so that read transactions can open it
+                let txn = database.begin_write()?;
+                txn.open_table(TRANSLATIONS)?;
+                txn.commit()?;
+                info!("Translation cache is stored in: {:?}", path);
+                Some(Arc::new(database))
+            }
+            None => None,
+        };
+
+        Ok(TranslationCache {
+            memory: Mutex::new(LruCache::new(capacity)),
+            disk,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    /// Translation of the text from the `source` language, detected by the backend if `None`
+    pub fn get(&self, backend: &str, source: Option<&str>, target: &str, text: &str) -> Option<String> {
+        let key = CacheKey::new(backend, source, target, text);
+
+        let mut translated = self.memory.lock().unwrap().get(&key).cloned();
+        if translated.is_none() {
+            translated = match self.disk_get(&key) {
+                Ok(translated) => translated,
+                Err(e) => {
+                    warn!("Error reading the translation cache: {:?}", e);
+                    None
+                }
+            };
+            // Promote the entry found on disk
+            if let Some(translated) = &translated {
+                self.memory.lock().unwrap().put(key, translated.clone());
+            }
+        }
+
+        let counter = match translated {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        debug!("Translation cache {} for: {:?}", if translated.is_some() { "hit" } else { "miss" }, text);
+        self.report();
+
+        translated
+    }
+
+    pub async fn put(&self, backend: &str, source: Option<&str>, target: &str, text: &str, translated: &str) {
+        let key = CacheKey::new(backend, source, target, text);
+        self.memory.lock().unwrap().put(key.clone(), translated.to_string());
+
+        // The commit waits for the disk, off the thread running the server
+        if let Some(database) = &self.disk {
+            let database = database.clone();
+            let translated = translated.to_string();
+            let written = tokio::task::spawn_blocking(move || Self::disk_put(&database, &key, &translated)).await;
+            if let Err(e) = written.map_err(anyhow::Error::from).and_then(|written| written) {
+                warn!("Error writing the translation cache: {:?}", e);
+            }
+        }
+    }
+
+    /// Number of cache hits and misses so far
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+
+    fn report(&self) {
+        let (hits, misses) = self.stats();
+        let total = hits + misses;
+        if total % REPORT_INTERVAL == 0 {
+            info!(
+                "Translation cache: {} hits, {} misses ({:.1}% hit rate)",
+                hits,
+                misses,
+                hits as f64 / total as f64 * 100.0,
+            );
+        }
+    }
+
+    fn disk_get(&self, key: &CacheKey) -> Result<Option<String>> {
+        let database = match &self.disk {
+            Some(database) => database,
+            None => return Ok(None),
+        };
+
+        let txn = database.begin_read()?;
+        let table = txn.open_table(TRANSLATIONS)?;
+        let translated = table.get(key.as_tuple())?;
+        Ok(translated.map(|translated| translated.value().to_string()))
+    }
+
+    fn disk_put(database: &Database, key: &CacheKey, translated: &str) -> Result<()> {
+        let txn = database.begin_write()?;
+        {
+            let mut table = txn.open_table(TRANSLATIONS)?;
+            table.insert(key.as_tuple(), translated)?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_hit_and_miss() {
+        let cache = TranslationCache::new(NonZeroUsize::new(2).unwrap(), None).unwrap();
+        assert_eq!(cache.get("aliyun", None, "zh", "gl hf"), None);
+        cache.put("aliyun", None, "zh", "gl hf", "祝好运").await;
+        assert_eq!(cache.get("aliyun", None, "zh", "gl hf"), Some("祝好运".to_string()));
+        // Different backend or target language is a different entry
+        assert_eq!(cache.get("openai", None, "zh", "gl hf"), None);
+        assert_eq!(cache.get("aliyun", None, "en", "gl hf"), None);
+        // So is a pinned source language
+        assert_eq!(cache.get("aliyun", Some("en"), "zh", "gl hf"), None);
+        assert_eq!(cache.stats(), (1, 4));
+    }
+
+    #[tokio::test]
+    async fn lru_eviction() {
+        let cache = TranslationCache::new(NonZeroUsize::new(1).unwrap(), None).unwrap();
+        cache.put("aliyun", None, "zh", "o7", "敬礼").await;
+        cache.put("aliyun", None, "zh", "gg wp", "打得好").await;
+        assert_eq!(cache.get("aliyun", None, "zh", "o7"), None);
+        assert_eq!(cache.get("aliyun", None, "zh", "gg wp"), Some("打得好".to_string()));
+    }
+
+    #[tokio::test]
+    async fn disk_persistence() {
+        let path = std::env::temp_dir().join(format!("chatrans-cache-{}.redb", uuid::Uuid::new_v4()));
+        {
+            let cache = TranslationCache::new(NonZeroUsize::new(1).unwrap(), Some(&path)).unwrap();
+            cache.put("aliyun", None, "zh", "o7", "敬礼").await;
+            cache.put("aliyun", None, "zh", "gg wp", "打得好").await;
+            // Evicted from memory but still on disk
+            assert_eq!(cache.get("aliyun", None, "zh", "o7"), Some("敬礼".to_string()));
+        }
+        let cache = TranslationCache::new(NonZeroUsize::new(1).unwrap(), Some(&path)).unwrap();
+        assert_eq!(cache.get("aliyun", None, "zh", "gg wp"), Some("打得好".to_string()));
+        std::fs::remove_file(path).unwrap();
+    }
+}
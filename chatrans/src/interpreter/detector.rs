@@ -0,0 +1,248 @@
+/**
+ * Offline language identification for chat messages.
+ *
+ * Scripts that belong to a single language (Kana, Hangul, Han, Thai, Arabic)
+ * are decided by counting characters, Han being told Simplified or Traditional
+ * Chinese by the characters written differently. Latin and Cyrillic messages
+ * are scored against character n-gram models built from the samples below.
+ */
+use std::{
+    collections::HashMap,
+    sync::OnceLock,
+};
+
+use crate::interpreter::Language;
+
+/// Longest n-gram used by the model
+const MAX_N: usize = 3;
+/// Messages with fewer letters are too short to be told apart by n-grams
+const MIN_LETTERS: usize = 4;
+/// Minimum average log-likelihood gap per n-gram between the best and the
+/// second best language
+const MIN_MARGIN: f64 = 0.1;
+
+/// Common characters written differently in Simplified and Traditional Chinese, in the same order.
+/// A message without any of them is taken as Simplified Chinese.
+const SIMPLIFIED_ONLY: &str = "们个这会来说时为国对过还点开没发问从见间现长体学动样实与头两机边无车给让听战舰队击帮区号领谢应该鱼飞";
+const TRADITIONAL_ONLY: &str = "們個這會來說時為國對過還點開沒發問從見間現長體學動樣實與頭兩機邊無車給讓聽戰艦隊擊幫區號領謝應該魚飛";
+
+const SAMPLES: &[(Language, &str)] = &[
+    (Language::EN, "Hello everyone, good luck and have fun. Please push with me to the point, the enemy destroyer is over there and they have no radar. Where is our carrier? I think we should go to the left side and wait for the battleships. Thank you for the help, well played. Why are you running away? Stay together and focus the cruiser first, then we can take the base and win this game. What are you doing, there is nobody on the right. Nice shot, that was a great torpedo hit."),
+    (Language::DE, "Hallo zusammen, viel Glück und viel Spaß. Bitte fahrt mit mir zum Punkt, der feindliche Zerstörer ist dort drüben und sie haben kein Radar. Wo ist unser Träger? Ich denke, wir sollten auf die linke Seite fahren und auf die Schlachtschiffe warten. Danke für die Hilfe, gut gespielt. Warum lauft ihr weg? Bleibt zusammen und schießt zuerst auf den Kreuzer, dann können wir die Basis einnehmen und das Spiel gewinnen. Was machst du, es ist niemand auf der rechten Seite. Schöner Schuss, das war ein großartiger Torpedotreffer."),
+    (Language::FR, "Bonjour à tous, bonne chance et amusez-vous bien. S'il vous plaît, poussez avec moi vers le point, le destroyer ennemi est là-bas et ils n'ont pas de radar. Où est notre porte-avions ? Je pense que nous devrions aller du côté gauche et attendre les cuirassés. Merci pour l'aide, bien joué. Pourquoi est-ce que vous fuyez ? Restez ensemble et visez d'abord le croiseur, ensuite nous pouvons prendre la base et gagner cette partie. Qu'est-ce que tu fais, il n'y a personne à droite. Beau tir, c'était une excellente torpille."),
+    (Language::ES, "Hola a todos, buena suerte y diviértanse. Por favor, empujad conmigo hacia el punto, el destructor enemigo está allí y no tienen radar. ¿Dónde está nuestro portaaviones? Creo que deberíamos ir por el lado izquierdo y esperar a los acorazados. Gracias por la ayuda, bien jugado. ¿Por qué estáis huyendo? Quedaos juntos y disparad primero al crucero, luego podemos tomar la base y ganar esta partida. ¿Qué estás haciendo? No hay nadie a la derecha. Buen disparo, fue un gran impacto de torpedo."),
+    (Language::PT, "Olá a todos, boa sorte e divirtam-se. Por favor, avancem comigo para o ponto, o contratorpedeiro inimigo está ali e eles não têm radar. Onde está o nosso porta-aviões? Acho que devemos ir pelo lado esquerdo e esperar pelos couraçados. Obrigado pela ajuda, bem jogado. Por que vocês estão fugindo? Fiquem juntos e atirem primeiro no cruzador, depois podemos tomar a base e ganhar esta partida. O que você está fazendo, não há ninguém à direita. Belo tiro, foi um ótimo acerto de torpedo."),
+    (Language::IT, "Ciao a tutti, buona fortuna e buon divertimento. Per favore, spingete con me verso il punto, il cacciatorpediniere nemico è laggiù e non hanno il radar. Dov'è la nostra portaerei? Penso che dovremmo andare sul lato sinistro e aspettare le corazzate. Grazie per l'aiuto, ben giocato. Perché state scappando? Restate uniti e colpite prima l'incrociatore, poi possiamo prendere la base e vincere questa partita. Cosa stai facendo, non c'è nessuno a destra. Bel colpo, è stato un ottimo siluro."),
+    (Language::PL, "Cześć wszystkim, powodzenia i dobrej zabawy. Proszę, płyńcie ze mną na punkt, wrogi niszczyciel jest tam i oni nie mają radaru. Gdzie jest nasz lotniskowiec? Myślę, że powinniśmy iść lewą stroną i poczekać na pancerniki. Dziękuję za pomoc, dobrze zagrane. Dlaczego uciekacie? Trzymajcie się razem i najpierw strzelajcie do krążownika, potem możemy zająć bazę i wygrać tę grę. Co ty robisz, nikogo nie ma po prawej. Ładny strzał, to było świetne trafienie torpedą."),
+    (Language::CS, "Ahoj všichni, hodně štěstí a hodně zábavy. Prosím, pojeďte se mnou na bod, nepřátelský torpédoborec je tamhle a nemají radar. Kde je naše letadlová loď? Myslím, že bychom měli jet po levé straně a počkat na bitevní lodě. Děkuji za pomoc, dobře zahráno. Proč utíkáte? Držte se pohromadě a nejdřív střílejte na křižník, potom můžeme obsadit základnu a vyhrát tuto hru. Co to děláš, vpravo nikdo není. Pěkná rána, to byl skvělý zásah torpédem."),
+    (Language::NL, "Hallo allemaal, veel succes en veel plezier. Alsjeblieft, duw met mij naar het punt, de vijandelijke torpedobootjager is daar en ze hebben geen radar. Waar is ons vliegdekschip? Ik denk dat we naar de linkerkant moeten gaan en op de slagschepen moeten wachten. Bedankt voor de hulp, goed gespeeld. Waarom rennen jullie weg? Blijf bij elkaar en schiet eerst op de kruiser, dan kunnen we de basis innemen en dit spel winnen. Wat doe je, er is niemand aan de rechterkant. Mooi schot, dat was een geweldige torpedotreffer."),
+    (Language::TR, "Herkese merhaba, bol şans ve iyi eğlenceler. Lütfen benimle noktaya doğru ilerleyin, düşman muhrip orada ve radarları yok. Uçak gemimiz nerede? Bence sol taraftan gitmeli ve zırhlıları beklemeliyiz. Yardım için teşekkürler, iyi oynadın. Neden kaçıyorsunuz? Birlikte kalın ve önce kruvazörü vurun, sonra üssü alıp bu oyunu kazanabiliriz. Ne yapıyorsun, sağda kimse yok. Güzel atış, harika bir torpido isabetiydi."),
+    (Language::VI, "Xin chào mọi người, chúc may mắn và chơi vui vẻ. Làm ơn tiến lên điểm cùng tôi, tàu khu trục của địch ở đằng kia và họ không có radar. Tàu sân bay của chúng ta ở đâu? Tôi nghĩ chúng ta nên đi bên trái và chờ các thiết giáp hạm. Cảm ơn đã giúp đỡ, chơi hay lắm. Tại sao các bạn lại bỏ chạy? Hãy ở cùng nhau và bắn tuần dương hạm trước, sau đó chúng ta có thể chiếm căn cứ và thắng trận này. Bạn đang làm gì vậy, bên phải không có ai. Bắn đẹp lắm, đó là một phát ngư lôi tuyệt vời."),
+    (Language::RU, "Всем привет, удачи и хорошей игры. Пожалуйста, идите со мной на точку, вражеский эсминец вон там, и у них нет радара. Где наш авианосец? Я думаю, нам надо идти по левому флангу и ждать линкоры. Спасибо за помощь, хорошо сыграно. Почему вы убегаете? Держитесь вместе и сначала стреляйте по крейсеру, потом мы можем взять базу и выиграть этот бой. Что ты делаешь, справа никого нет. Хороший выстрел, это было отличное попадание торпедой."),
+    (Language::UK, "Всім привіт, удачі та гарної гри. Будь ласка, йдіть зі мною на точку, ворожий есмінець он там, і в них немає радара. Де наш авіаносець? Я думаю, нам треба йти лівим флангом і чекати на лінкори. Дякую за допомогу, добре зіграно. Чому ви тікаєте? Тримайтеся разом і спочатку стріляйте по крейсеру, потім ми можемо взяти базу та виграти цей бій. Що ти робиш, праворуч нікого немає. Гарний постріл, це було чудове влучання торпедою."),
+    (Language::BE, "Усім прывітанне, поспеху і добрай гульні. Калі ласка, ідзіце са мной на кропку, варожы эсмінец вунь там, і ў іх няма радара. Дзе наш авіяносец? Я думаю, нам трэба ісці па левым флангу і чакаць лінкоры. Дзякуй за дапамогу, добра згуляна. Чаму вы ўцякаеце? Трымайцеся разам і спачатку страляйце па крэйсеры, потым мы можам узяць базу і выйграць гэты бой."),
+    (Language::KK, "Барлығыңызға сәлем, сәттілік және жақсы ойын. Өтінемін, менімен бірге нүктеге барыңыздар, жаудың эсминеці анда және оларда радар жоқ. Біздің авианосец қайда? Менің ойымша, біз сол қанатпен жүріп, линкорларды күтуіміз керек. Көмегіңіз үшін рахмет, жақсы ойнадыңыз. Неге қашып барасыздар? Бірге болыңыздар және алдымен крейсерді атыңыздар, содан кейін базаны алып, осы ұрысты жеңе аламыз."),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Script {
+    Latin,
+    Cyrillic,
+    Han,
+    Kana,
+    Hangul,
+    Thai,
+    Arabic,
+}
+
+impl Script {
+    fn of(c: char) -> Option<Script> {
+        match c {
+            'a'..='z' | 'A'..='Z' | '\u{00C0}'..='\u{024F}' | '\u{1E00}'..='\u{1EFF}' => Some(Script::Latin),
+            '\u{0400}'..='\u{04FF}' | '\u{0500}'..='\u{052F}' => Some(Script::Cyrillic),
+            '\u{3040}'..='\u{30FF}' | '\u{31F0}'..='\u{31FF}' | '\u{FF66}'..='\u{FF9F}' => Some(Script::Kana),
+            '\u{4E00}'..='\u{9FFF}' | '\u{3400}'..='\u{4DBF}' | '\u{F900}'..='\u{FAFF}' => Some(Script::Han),
+            '\u{AC00}'..='\u{D7AF}' | '\u{1100}'..='\u{11FF}' | '\u{3130}'..='\u{318F}' => Some(Script::Hangul),
+            '\u{0E00}'..='\u{0E7F}' => Some(Script::Thai),
+            '\u{0600}'..='\u{06FF}' | '\u{0750}'..='\u{077F}' => Some(Script::Arabic),
+            _ => None,
+        }
+    }
+}
+
+/// Character n-gram log probabilities of a language
+struct Model {
+    language: Language,
+    script: Script,
+    log_probs: HashMap<String, f64>,
+    /// Log probability of an n-gram never seen in the sample
+    unseen: f64,
+}
+
+impl Model {
+    fn new(language: Language, sample: &str) -> Model {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for ngram in ngrams(sample) {
+            *counts.entry(ngram).or_default() += 1;
+        }
+
+        // Additive smoothing, with room for as many unseen n-grams as seen ones
+        let total = counts.values().sum::<usize>() as f64;
+        let vocabulary = 2.0 * counts.len() as f64;
+        let log_probs = counts.into_iter()
+            .map(|(ngram, count)| (ngram, ((count as f64 + 1.0) / (total + vocabulary)).ln()))
+            .collect();
+        let script = sample.chars()
+            .find_map(Script::of)
+            .unwrap_or(Script::Latin);
+
+        Model {
+            language,
+            script,
+            log_probs,
+            unseen: (1.0 / (total + vocabulary)).ln(),
+        }
+    }
+
+    fn score(&self, ngrams: &[String]) -> f64 {
+        ngrams.iter()
+            .map(|ngram| *self.log_probs.get(ngram).unwrap_or(&self.unseen))
+            .sum()
+    }
+}
+
+fn models() -> &'static [Model] {
+    static MODELS: OnceLock<Vec<Model>> = OnceLock::new();
+    MODELS.get_or_init(|| {
+        SAMPLES.iter()
+            .map(|(language, sample)| Model::new(*language, sample))
+            .collect()
+    })
+}
+
+/// All n-grams up to `MAX_N` of the lowercase words, padded with spaces
+fn ngrams(text: &str) -> Vec<String> {
+    let mut ngrams = Vec::new();
+    let lowercase = text.to_lowercase();
+    let words = lowercase.split(|c: char| !c.is_alphabetic()).filter(|word| !word.is_empty());
+    for word in words {
+        let chars: Vec<char> = format!(" {} ", word).chars().collect();
+        for n in 1..=MAX_N {
+            for window in chars.windows(n) {
+                if n == 1 && window[0] == ' ' {
+                    continue;
+                }
+                ngrams.push(window.iter().collect());
+            }
+        }
+    }
+    ngrams
+}
+
+/// Whether the character is a vowel, counting any accented letter as one
+fn is_latin_vowel(c: char) -> bool {
+    match c.to_ascii_lowercase() {
+        'a' | 'e' | 'i' | 'o' | 'u' | 'y' => true,
+        c => !c.is_ascii() && Script::of(c) == Some(Script::Latin),
+    }
+}
+
+/// Simplified or Traditional Chinese, by the characters only one of them uses
+fn chinese_variant(text: &str) -> Language {
+    let count = |variant: &str| text.chars().filter(|c| variant.contains(*c)).count();
+    if count(TRADITIONAL_ONLY) > count(SIMPLIFIED_ONLY) {
+        Language::ZHTW
+    } else {
+        Language::ZH
+    }
+}
+
+/// Detect the language of a chat message, `None` if it cannot be told
+/// with enough confidence (e.g. "o7", "gg" or a player name)
+pub fn detect_language(text: &str) -> Option<Language> {
+    let mut scripts: HashMap<Script, usize> = HashMap::new();
+    for c in text.chars() {
+        if let Some(script) = Script::of(c) {
+            *scripts.entry(script).or_default() += 1;
+        }
+    }
+
+    // Kana only appears in Japanese, which also uses Han characters
+    if scripts.contains_key(&Script::Kana) {
+        return Some(Language::JA);
+    }
+    let (script, letters) = scripts.into_iter().max_by_key(|(_, count)| *count)?;
+    match script {
+        Script::Han => return Some(chinese_variant(text)),
+        Script::Hangul => return Some(Language::KO),
+        Script::Thai => return Some(Language::TH),
+        Script::Arabic => return Some(Language::AR),
+        Script::Kana | Script::Latin | Script::Cyrillic => {}
+    }
+    if letters < MIN_LETTERS {
+        return None;
+    }
+    // Abbreviations such as "gl hf" or "wp" have no vowel to go by
+    if script == Script::Latin && !text.chars().any(is_latin_vowel) {
+        return None;
+    }
+
+    let ngrams = ngrams(text);
+    let mut scores: Vec<(Language, f64)> = models().iter()
+        .filter(|model| model.script == script)
+        .map(|model| (model.language, model.score(&ngrams)))
+        .collect();
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    match scores.as_slice() {
+        [(best, _)] => Some(*best),
+        [(best, best_score), (_, second_score), ..] => {
+            let margin = (best_score - second_score) / ngrams.len() as f64;
+            if margin >= MIN_MARGIN { Some(*best) } else { None }
+        }
+        [] => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn single_language_scripts() {
+        assert_eq!(detect_language("大家好，加油"), Some(Language::ZH));
+        assert_eq!(detect_language("驱逐舰在哪里？我们去占领B点"), Some(Language::ZH));
+        assert_eq!(detect_language("驅逐艦在哪裡？我們去佔領B點"), Some(Language::ZHTW));
+        assert_eq!(detect_language("謝謝幫忙"), Some(Language::ZHTW));
+        assert_eq!(detect_language("よろしくお願いします"), Some(Language::JA));
+        assert_eq!(detect_language("駆逐艦はどこ？"), Some(Language::JA));
+        assert_eq!(detect_language("안녕하세요"), Some(Language::KO));
+    }
+
+    #[test]
+    fn too_short_or_no_letters() {
+        assert_eq!(detect_language("o7"), None);
+        assert_eq!(detect_language("gg"), None);
+        assert_eq!(detect_language("gl hf"), None);
+        assert_eq!(detect_language("1234 !!!"), None);
+        assert_eq!(detect_language(""), None);
+    }
+
+    #[test]
+    fn cyrillic_languages() {
+        assert_eq!(detect_language("ребята, держитесь вместе"), Some(Language::RU));
+        assert_eq!(detect_language("где наш авианосец?"), Some(Language::RU));
+        assert_eq!(detect_language("дякую, гарна гра"), Some(Language::UK));
+        assert_eq!(detect_language("нам трэба ісці налева"), Some(Language::BE));
+    }
+
+    #[test]
+    fn latin_languages() {
+        assert_eq!(detect_language("where is our carrier"), Some(Language::EN));
+        assert_eq!(detect_language("thanks for the help guys"), Some(Language::EN));
+        assert_eq!(detect_language("wo ist unser Träger"), Some(Language::DE));
+        assert_eq!(detect_language("merci, bien joué"), Some(Language::FR));
+        assert_eq!(detect_language("gracias por la ayuda"), Some(Language::ES));
+        assert_eq!(detect_language("dziękuję za pomoc"), Some(Language::PL));
+    }
+}
@@ -0,0 +1,48 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TranslateError {
+    #[error("Network error: {0}")]
+    Network(#[from] reqwest::Error),
+    #[error("Authentication failed: {0}")]
+    Auth(String),
+    #[error("Quota exhausted: {0}")]
+    Quota(String),
+    #[error("Rate limited: {0}")]
+    RateLimited(String),
+    #[error("Service unavailable: {0}")]
+    Service(String),
+    #[error("Request rejected: {0}")]
+    Rejected(String),
+    #[error("Malformed response: {0}")]
+    MalformedResponse(String),
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+}
+
+impl TranslateError {
+    /// Classify an unsuccessful HTTP response
+    pub fn from_status(status: reqwest::StatusCode, body: String) -> TranslateError {
+        match status.as_u16() {
+            401 | 403 => TranslateError::Auth(body),
+            402 => TranslateError::Quota(body),
+            429 => TranslateError::RateLimited(body),
+            500..=599 => TranslateError::Service(body),
+            _ => TranslateError::Rejected(format!("{} {}", status, body)),
+        }
+    }
+
+    /// Whether the same request may succeed if sent again later
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            TranslateError::Network(_) | TranslateError::RateLimited(_) | TranslateError::Service(_)
+        )
+    }
+}
+
+impl From<serde_json::Error> for TranslateError {
+    fn from(e: serde_json::Error) -> Self {
+        TranslateError::MalformedResponse(e.to_string())
+    }
+}
@@ -1,93 +1,272 @@
-use serde_json;
-use tracing::warn;
+use rand::Rng;
+use tokio::time::{Duration, Instant, sleep};
+use tracing::{debug, info, warn};
 
-use crate::interpreter::aliyun_cli::AliyunCli;
+use crate::interpreter::{Language, TranslateError, TranslationBackend, TranslationCache};
 
-const SERVER_URL: &str = "http://mt.cn-hangzhou.aliyuncs.com/api/translate/web/ecommerce";
-const FORMAT_TYPE: &str = "text";
-const SOURCE_LANGUAGE: &str = "auto";
-const SCENE: &str = "social";
+/// Number of retries after a failed translation
+const MAX_RETRIES: u32 = 3;
+/// Delay before the first retry, doubled for each of the next ones
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+/// Longest time spent retrying a translation, which holds up the next messages.
+/// A request timing out already takes longer, and is not retried.
+const MAX_RETRY_TIME: Duration = Duration::from_secs(3);
+/// Longest time the server waits for the backend at startup before serving
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
 
-pub enum Language {
-    ZH,
-    EN,
+pub struct Interpreter {
+    /// Default target language, for clients not requesting one
+    language: Language,
+    /// Pinned source language, detected by the backend if `None`
+    source_language: Option<Language>,
+    backend: Option<Box<dyn TranslationBackend>>,
+    cache: Option<TranslationCache>,
 }
 
-impl From<String> for Language {
-    fn from(s: String) -> Self {
-        match s.as_str() {
-            "zh" => Language::ZH,
-            "en" => Language::EN,
-            _ => {
-                warn!("Invalid language: {}, default to `zh`", s);
-                Language::ZH
+impl Interpreter {
+    pub fn new(
+        language: Language,
+        backend: Option<Box<dyn TranslationBackend>>,
+    ) -> Interpreter {
+        Interpreter {
+            language,
+            source_language: None,
+            backend,
+            cache: None,
+        }
+    }
+
+    /// Translate from the given language instead of letting the backend detect it
+    pub fn with_source_language(mut self, language: Language) -> Interpreter {
+        self.source_language = Some(language);
+        self
+    }
+
+    /// Look up translations in the cache before calling the backend
+    pub fn with_cache(mut self, cache: TranslationCache) -> Interpreter {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Default target language
+    pub fn language(&self) -> Language {
+        self.language
+    }
+
+    /// Check the backend and log the result
+    pub async fn health_check(&self) {
+        let backend = match &self.backend {
+            Some(backend) => backend,
+            None => return,
+        };
+
+        let checked = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, backend.health_check()).await {
+            Ok(checked) => checked,
+            Err(_) => {
+                warn!("Translation backend `{}` did not answer within {:?}", backend.name(), HEALTH_CHECK_TIMEOUT);
+                return;
+            }
+        };
+        match checked {
+            Ok(_) => {
+                info!("Translation backend `{}` is ready", backend.name());
+                let supported = backend.supported_languages();
+                let languages = std::iter::once(&self.language).chain(self.source_language.iter());
+                for language in languages {
+                    if !supported.contains(language) {
+                        warn!(
+                            "Language `{}` is not supported by the `{}` backend",
+                            language,
+                            backend.name(),
+                        );
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Translation backend `{}` is not available: {}", backend.name(), e);
             }
         }
     }
-}
 
-impl Language {
-    pub fn to_string(&self) -> String {
-        match self {
-            Language::ZH => "zh".to_string(),
-            Language::EN => "en".to_string(),
+    /// Translate the text written in the `detected` language into `target`, `None`
+    /// if no backend is configured or the text is already in the target language
+    pub async fn translate(
+        &self,
+        text: &str,
+        detected: Option<Language>,
+        target: Language,
+    ) -> Result<Option<String>, TranslateError> {
+        let backend = match &self.backend {
+            Some(backend) => backend,
+            None => return Ok(None),
+        };
+        // A pinned source language takes precedence over the detected one
+        if self.source_language.or(detected) == Some(target) {
+            debug!("Skipping translation of a message already in `{}`: {:?}", target, text);
+            return Ok(None);
+        }
+        let source_code = self.source_language.map(|language| language.to_string());
+        let target_code = target.to_string();
+
+        if let Some(cache) = &self.cache {
+            if let Some(translated) = cache.get(backend.name(), source_code.as_deref(), &target_code, text) {
+                return Ok(Some(translated));
+            }
         }
+
+        let translated = Self::translate_with_retry(
+            backend.as_ref(),
+            text,
+            self.source_language.as_ref(),
+            &target,
+        ).await?;
+        if let Some(cache) = &self.cache {
+            cache.put(backend.name(), source_code.as_deref(), &target_code, text, &translated).await;
+        }
+        Ok(Some(translated))
     }
-}
 
-pub struct Interpreter {
-    language: Language,
-    aliyun_cli: Option<AliyunCli>,
+    /// Call the backend, retrying transient failures with exponential backoff and jitter
+    async fn translate_with_retry(
+        backend: &dyn TranslationBackend,
+        text: &str,
+        source: Option<&Language>,
+        target: &Language,
+    ) -> Result<String, TranslateError> {
+        let started = Instant::now();
+        let mut attempt = 0;
+        loop {
+            match backend.translate(text, source, target).await {
+                Ok(translated) => return Ok(translated),
+                Err(e) if e.is_retryable() && attempt < MAX_RETRIES => {
+                    let delay = RETRY_BASE_DELAY * 2u32.pow(attempt);
+                    let jitter = rand::thread_rng().gen_range(Duration::ZERO..=delay / 2);
+                    if started.elapsed() + delay + jitter > MAX_RETRY_TIME {
+                        warn!("Translation failed ({}), giving up after {:?}", e, started.elapsed());
+                        return Err(e);
+                    }
+                    warn!(
+                        "Translation failed ({}), retrying in {:?} ({}/{})",
+                        e,
+                        delay + jitter,
+                        attempt + 1,
+                        MAX_RETRIES,
+                    );
+                    sleep(delay + jitter).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
 }
 
-impl Interpreter {
-    pub fn new(
-        language: String,
-        access_key_id: Option<String>,
-        access_key_secret: Option<String>,
-    ) -> Interpreter {
-        let language = Language::from(language);
-        let aliyun_cli = match (access_key_id, access_key_secret) {
-            (Some(id), Some(secret)) => Some(AliyunCli::new(id, secret)),
-            _ => None,
-        };
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use async_trait::async_trait;
+    use super::*;
 
-        Interpreter {
-            language,
-            aliyun_cli,
+    /// Backend failing with the error a number of times before translating
+    struct FailingBackend {
+        failures: u32,
+        error: fn() -> TranslateError,
+        calls: AtomicU32,
+    }
+
+    impl FailingBackend {
+        fn new(failures: u32, error: fn() -> TranslateError) -> FailingBackend {
+            FailingBackend { failures, error, calls: AtomicU32::new(0) }
         }
     }
 
-    pub async fn translate(&self, text: String) -> Option<String> {
-        if self.aliyun_cli.is_none() {
-            return None
+    #[async_trait]
+    impl TranslationBackend for FailingBackend {
+        fn name(&self) -> &str {
+            "failing"
         }
 
-        // Format the post body
-        let post_body = format!(
-            "{{\n\"FormatType\": \"{}\",\n\"SourceLanguage\": \"{}\",\n\"TargetLanguage\": \"{}\",\n\"SourceText\": \"{}\",\n\"Scene\": \"{}\"\n}}",
-            FORMAT_TYPE,
-            SOURCE_LANGUAGE,
-            self.language.to_string(),
-            text,
-            SCENE
-        );
-
-        // Send the post request
-        let response = self.aliyun_cli.as_ref().unwrap().send_post(
-            SERVER_URL.to_string(),
-            post_body,
-        ).await;
-
-        // Parse the response
-        let response: serde_json::Value = serde_json::from_str(&response).unwrap();
-        // Check if the response is valid
-        if response["Code"] != "200" || response["Data"]["Translated"].is_null() {
-            warn!("Failed to translate the text: {:?}", response);
-            return Some("[WARN] Failed to translate the text through the given API".to_string())
+        async fn translate(&self, _text: &str, _source: Option<&Language>, _target: &Language) -> Result<String, TranslateError> {
+            if self.calls.fetch_add(1, Ordering::Relaxed) < self.failures {
+                Err((self.error)())
+            } else {
+                Ok("祝好运".to_string())
+            }
+        }
+
+        fn supported_languages(&self) -> Vec<Language> {
+            Language::all()
+        }
+
+        async fn health_check(&self) -> Result<(), TranslateError> {
+            Ok(())
+        }
+    }
+
+    /// Backend whose server never answers the health check
+    struct StalledBackend;
+
+    #[async_trait]
+    impl TranslationBackend for StalledBackend {
+        fn name(&self) -> &str {
+            "stalled"
         }
 
-        // Return the translated text
-        Some(response["Data"]["Translated"].as_str().unwrap().to_string())
+        async fn translate(&self, _text: &str, _source: Option<&Language>, _target: &Language) -> Result<String, TranslateError> {
+            std::future::pending().await
+        }
+
+        fn supported_languages(&self) -> Vec<Language> {
+            Language::all()
+        }
+
+        async fn health_check(&self) -> Result<(), TranslateError> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn health_check_gives_up() {
+        let interpreter = Interpreter::new(Language::ZH, Some(Box::new(StalledBackend)));
+        let started = Instant::now();
+        interpreter.health_check().await;
+        assert_eq!(started.elapsed(), HEALTH_CHECK_TIMEOUT);
+    }
+
+    fn unavailable() -> TranslateError {
+        TranslateError::Service("503 Service Unavailable".to_string())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_with_backoff() {
+        let backend = FailingBackend::new(2, unavailable);
+        let started = Instant::now();
+        let translated = Interpreter::translate_with_retry(&backend, "gl hf", None, &Language::ZH).await;
+        assert_eq!(translated.unwrap(), "祝好运");
+        assert_eq!(backend.calls.load(Ordering::Relaxed), 3);
+        // The second delay is doubled, each with up to half of it as jitter
+        let waited = started.elapsed();
+        assert!(waited >= RETRY_BASE_DELAY * 3 && waited <= RETRY_BASE_DELAY * 3 * 3 / 2, "{:?}", waited);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_in_time() {
+        let backend = FailingBackend::new(u32::MAX, unavailable);
+        let started = Instant::now();
+        let translated = Interpreter::translate_with_retry(&backend, "gl hf", None, &Language::ZH).await;
+        assert!(matches!(translated, Err(TranslateError::Service(_))));
+        let calls = backend.calls.load(Ordering::Relaxed);
+        assert!((2..=MAX_RETRIES + 1).contains(&calls), "{}", calls);
+        assert!(started.elapsed() <= MAX_RETRY_TIME);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_retry_of_permanent_errors() {
+        let backend = FailingBackend::new(1, || TranslateError::Auth("InvalidAccessKeyId.NotFound".to_string()));
+        let started = Instant::now();
+        let translated = Interpreter::translate_with_retry(&backend, "gl hf", None, &Language::ZH).await;
+        assert!(matches!(translated, Err(TranslateError::Auth(_))));
+        assert_eq!(backend.calls.load(Ordering::Relaxed), 1);
+        assert_eq!(started.elapsed(), Duration::ZERO);
     }
 }
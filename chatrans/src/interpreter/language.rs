@@ -0,0 +1,197 @@
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("Unknown language `{0}`, expected a BCP-47 code such as `zh`, `en` or `ru`")]
+pub struct UnknownLanguage(pub String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Language {
+    /// Simplified Chinese
+    ZH,
+    /// Traditional Chinese
+    ZHTW,
+    EN,
+    RU,
+    UK,
+    BE,
+    KK,
+    JA,
+    KO,
+    DE,
+    FR,
+    ES,
+    PT,
+    IT,
+    PL,
+    CS,
+    NL,
+    TR,
+    VI,
+    TH,
+    AR,
+}
+
+impl Language {
+    pub fn all() -> Vec<Language> {
+        vec![
+            Language::ZH,
+            Language::ZHTW,
+            Language::EN,
+            Language::RU,
+            Language::UK,
+            Language::BE,
+            Language::KK,
+            Language::JA,
+            Language::KO,
+            Language::DE,
+            Language::FR,
+            Language::ES,
+            Language::PT,
+            Language::IT,
+            Language::PL,
+            Language::CS,
+            Language::NL,
+            Language::TR,
+            Language::VI,
+            Language::TH,
+            Language::AR,
+        ]
+    }
+
+    /// The BCP-47 code of the language
+    pub fn code(&self) -> &'static str {
+        match self {
+            Language::ZH => "zh",
+            Language::ZHTW => "zh-TW",
+            Language::EN => "en",
+            Language::RU => "ru",
+            Language::UK => "uk",
+            Language::BE => "be",
+            Language::KK => "kk",
+            Language::JA => "ja",
+            Language::KO => "ko",
+            Language::DE => "de",
+            Language::FR => "fr",
+            Language::ES => "es",
+            Language::PT => "pt",
+            Language::IT => "it",
+            Language::PL => "pl",
+            Language::CS => "cs",
+            Language::NL => "nl",
+            Language::TR => "tr",
+            Language::VI => "vi",
+            Language::TH => "th",
+            Language::AR => "ar",
+        }
+    }
+
+    /// English name of the language
+    pub fn name(&self) -> &'static str {
+        match self {
+            Language::ZH => "Simplified Chinese",
+            Language::ZHTW => "Traditional Chinese",
+            Language::EN => "English",
+            Language::RU => "Russian",
+            Language::UK => "Ukrainian",
+            Language::BE => "Belarusian",
+            Language::KK => "Kazakh",
+            Language::JA => "Japanese",
+            Language::KO => "Korean",
+            Language::DE => "German",
+            Language::FR => "French",
+            Language::ES => "Spanish",
+            Language::PT => "Portuguese",
+            Language::IT => "Italian",
+            Language::PL => "Polish",
+            Language::CS => "Czech",
+            Language::NL => "Dutch",
+            Language::TR => "Turkish",
+            Language::VI => "Vietnamese",
+            Language::TH => "Thai",
+            Language::AR => "Arabic",
+        }
+    }
+}
+
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl FromStr for Language {
+    type Err = UnknownLanguage;
+
+    /// Parse a BCP-47 language tag, e.g. `en`, `en-US` or `zh-Hant-TW`.
+    /// Only the language and, for Chinese, the script or region are considered.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lowercase = s.trim().to_lowercase();
+        let mut subtags = lowercase.split(['-', '_']);
+        let primary = subtags.next().unwrap_or_default();
+
+        let language = match primary {
+            "zh" => {
+                let traditional = subtags.any(|subtag| matches!(subtag, "hant" | "tw" | "hk" | "mo"));
+                if traditional { Language::ZHTW } else { Language::ZH }
+            }
+            "en" => Language::EN,
+            "ru" => Language::RU,
+            "uk" => Language::UK,
+            "be" => Language::BE,
+            "kk" => Language::KK,
+            "ja" => Language::JA,
+            "ko" => Language::KO,
+            "de" => Language::DE,
+            "fr" => Language::FR,
+            "es" => Language::ES,
+            "pt" => Language::PT,
+            "it" => Language::IT,
+            "pl" => Language::PL,
+            "cs" => Language::CS,
+            "nl" => Language::NL,
+            "tr" => Language::TR,
+            "vi" => Language::VI,
+            "th" => Language::TH,
+            "ar" => Language::AR,
+            _ => return Err(UnknownLanguage(s.to_string())),
+        };
+        Ok(language)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_primary_subtag() {
+        assert_eq!("ru".parse::<Language>().unwrap(), Language::RU);
+        assert_eq!("EN".parse::<Language>().unwrap(), Language::EN);
+        assert_eq!("en-US".parse::<Language>().unwrap(), Language::EN);
+        assert_eq!("pt_BR".parse::<Language>().unwrap(), Language::PT);
+    }
+
+    #[test]
+    fn parse_chinese_variants() {
+        assert_eq!("zh".parse::<Language>().unwrap(), Language::ZH);
+        assert_eq!("zh-CN".parse::<Language>().unwrap(), Language::ZH);
+        assert_eq!("zh-Hans".parse::<Language>().unwrap(), Language::ZH);
+        assert_eq!("zh-TW".parse::<Language>().unwrap(), Language::ZHTW);
+        assert_eq!("zh-Hant-HK".parse::<Language>().unwrap(), Language::ZHTW);
+    }
+
+    #[test]
+    fn parse_unknown() {
+        assert!("xx".parse::<Language>().is_err());
+        assert!("".parse::<Language>().is_err());
+        assert!("auto".parse::<Language>().is_err());
+    }
+
+    #[test]
+    fn code_round_trip() {
+        for language in Language::all() {
+            assert_eq!(language.code().parse::<Language>().unwrap(), language);
+        }
+    }
+}
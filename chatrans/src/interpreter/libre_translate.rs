@@ -0,0 +1,283 @@
+/**
+ * Reference: https://libretranslate.com/docs
+ */
+use std::sync::RwLock;
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::interpreter::{Language, TranslateError, TranslationBackend};
+use crate::interpreter::backend::http_client;
+
+/// Let the server detect the source language
+const SOURCE_LANGUAGE_AUTO: &str = "auto";
+const FORMAT_TYPE: &str = "text";
+
+#[derive(Serialize)]
+struct TranslateRequest<'a> {
+    q: &'a str,
+    source: &'a str,
+    target: &'a str,
+    format: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_key: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TranslateResponse {
+    translated_text: String,
+}
+
+#[derive(Serialize)]
+struct DetectRequest<'a> {
+    q: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_key: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct Detection {
+    confidence: f32,
+    language: String,
+}
+
+#[derive(Deserialize)]
+struct LanguageEntry {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Client of a LibreTranslate compatible server
+pub struct LibreTranslate {
+    url: String,
+    api_key: Option<String>,
+    client: reqwest::Client,
+    /// Languages reported by `/languages`, fetched on health check
+    languages: RwLock<Vec<Language>>,
+}
+
+impl LibreTranslate {
+    pub fn new(url: String, api_key: Option<String>) -> LibreTranslate {
+        LibreTranslate {
+            url: url.trim_end_matches('/').to_string(),
+            api_key,
+            client: http_client(),
+            languages: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Decode the response, turning the `error` field of a failed request into an error
+    async fn parse_response<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, TranslateError> {
+        let status = response.status();
+        let body = response.text().await?;
+        debug!("LibreTranslate response: {} {}", status, body);
+
+        if !status.is_success() {
+            let message = match serde_json::from_str::<ErrorResponse>(&body) {
+                Ok(e) => e.error,
+                Err(_) => body,
+            };
+            return Err(TranslateError::from_status(status, message));
+        }
+
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    /// LibreTranslate calls Traditional Chinese `zt`
+    fn language_code(language: &Language) -> &'static str {
+        match language {
+            Language::ZHTW => "zt",
+            language => language.code(),
+        }
+    }
+
+    fn parse_language(code: &str) -> Option<Language> {
+        match code {
+            "zt" => Some(Language::ZHTW),
+            code => code.parse().ok(),
+        }
+    }
+
+    async fn fetch_languages(&self) -> Result<Vec<Language>, TranslateError> {
+        let response = self.client.get(format!("{}/languages", self.url))
+            .send()
+            .await?;
+        let entries: Vec<LanguageEntry> = Self::parse_response(response).await?;
+
+        Ok(entries.iter()
+            .filter_map(|entry| Self::parse_language(&entry.code))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl TranslationBackend for LibreTranslate {
+    fn name(&self) -> &str {
+        "libre-translate"
+    }
+
+    async fn translate(
+        &self,
+        text: &str,
+        source: Option<&Language>,
+        target: &Language,
+    ) -> Result<String, TranslateError> {
+        let request = TranslateRequest {
+            q: text,
+            source: source.map_or(SOURCE_LANGUAGE_AUTO, Self::language_code),
+            target: Self::language_code(target),
+            format: FORMAT_TYPE,
+            api_key: self.api_key.as_deref(),
+        };
+
+        let response = self.client.post(format!("{}/translate", self.url))
+            .json(&request)
+            .send()
+            .await?;
+        let response: TranslateResponse = Self::parse_response(response).await?;
+
+        Ok(response.translated_text)
+    }
+
+    async fn detect(&self, text: &str) -> Result<Option<Language>, TranslateError> {
+        let request = DetectRequest {
+            q: text,
+            api_key: self.api_key.as_deref(),
+        };
+
+        let response = self.client.post(format!("{}/detect", self.url))
+            .json(&request)
+            .send()
+            .await?;
+        let detections: Vec<Detection> = Self::parse_response(response).await?;
+
+        // The detections are not guaranteed to be sorted by confidence
+        Ok(detections.iter()
+            .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+            .and_then(|detection| Self::parse_language(&detection.language)))
+    }
+
+    fn supported_languages(&self) -> Vec<Language> {
+        self.languages.read().unwrap().clone()
+    }
+
+    async fn health_check(&self) -> Result<(), TranslateError> {
+        let languages = self.fetch_languages().await?;
+        *self.languages.write().unwrap() = languages;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+    use crate::interpreter::backend::mock_endpoint;
+    use super::*;
+
+    async fn mock_server() -> (TcpListener, LibreTranslate) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        (listener, LibreTranslate::new(url, Some("key".to_string())))
+    }
+
+    #[tokio::test]
+    async fn traditional_chinese_code() {
+        let (listener, libre) = mock_server().await;
+
+        let (translated, (head, body)) = tokio::join!(
+            libre.translate("gg", Some(&Language::ZHTW), &Language::ZHTW),
+            mock_endpoint(&listener, "200 OK", r#"{"translatedText":"好局"}"#),
+        );
+        assert_eq!(translated.unwrap(), "好局");
+        assert!(head.starts_with("POST /translate "));
+        let request: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(request["q"], "gg");
+        assert_eq!(request["source"], "zt");
+        assert_eq!(request["target"], "zt");
+        assert_eq!(request["format"], "text");
+        assert_eq!(request["api_key"], "key");
+
+        let (translated, (_, body)) = tokio::join!(
+            libre.translate("gg", None, &Language::EN),
+            mock_endpoint(&listener, "200 OK", r#"{"translatedText":"gg"}"#),
+        );
+        assert_eq!(translated.unwrap(), "gg");
+        let request: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(request["source"], "auto");
+        assert_eq!(request["target"], "en");
+
+        let languages = r#"[{"code":"en","name":"English"},{"code":"zt","name":"Chinese (traditional)"},{"code":"xx","name":"Unknown"}]"#;
+        let (checked, _) = tokio::join!(libre.health_check(), mock_endpoint(&listener, "200 OK", languages));
+        assert!(checked.is_ok());
+        assert_eq!(libre.supported_languages(), [Language::EN, Language::ZHTW]);
+    }
+
+    #[tokio::test]
+    async fn error_field() {
+        let (listener, libre) = mock_server().await;
+
+        let response = r#"{"error":"zz is not supported"}"#;
+        let (translated, _) = tokio::join!(
+            libre.translate("gg", None, &Language::EN),
+            mock_endpoint(&listener, "400 Bad Request", response),
+        );
+        match translated {
+            Err(TranslateError::Rejected(message)) => assert!(message.ends_with("zz is not supported")),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let response = r#"{"error":"Invalid API key"}"#;
+        let (translated, _) = tokio::join!(
+            libre.translate("gg", None, &Language::EN),
+            mock_endpoint(&listener, "403 Forbidden", response),
+        );
+        assert!(matches!(translated, Err(TranslateError::Auth(message)) if message == "Invalid API key"));
+
+        // Not from the server itself, such as a proxy in front of it
+        let (detected, _) = tokio::join!(
+            libre.detect("gg"),
+            mock_endpoint(&listener, "502 Bad Gateway", r#""bad gateway""#),
+        );
+        assert!(matches!(detected, Err(TranslateError::Service(message)) if message == r#""bad gateway""#));
+    }
+
+    #[tokio::test]
+    async fn detection_with_highest_confidence() {
+        let (listener, libre) = mock_server().await;
+
+        let detections = r#"[{"confidence":40.0,"language":"en"},{"confidence":92.5,"language":"zt"},{"confidence":61.0,"language":"ru"}]"#;
+        let (detected, (head, body)) = tokio::join!(libre.detect("好局"), mock_endpoint(&listener, "200 OK", detections));
+        assert_eq!(detected.unwrap(), Some(Language::ZHTW));
+        assert!(head.starts_with("POST /detect "));
+        let request: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(request["q"], "好局");
+
+        let (detected, _) = tokio::join!(libre.detect("?"), mock_endpoint(&listener, "200 OK", "[]"));
+        assert_eq!(detected.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn languages_after_health_check() {
+        let (listener, libre) = mock_server().await;
+        assert!(libre.supported_languages().is_empty());
+
+        let (checked, _) = tokio::join!(
+            libre.health_check(),
+            mock_endpoint(&listener, "500 Internal Server Error", r#"{"error":"Starting"}"#),
+        );
+        assert!(matches!(checked, Err(TranslateError::Service(_))));
+        assert!(libre.supported_languages().is_empty());
+
+        let languages = r#"[{"code":"en","name":"English"},{"code":"ru","name":"Russian"}]"#;
+        let (checked, (head, _)) = tokio::join!(libre.health_check(), mock_endpoint(&listener, "200 OK", languages));
+        assert!(checked.is_ok());
+        assert!(head.starts_with("GET /languages "));
+        assert_eq!(libre.supported_languages(), [Language::EN, Language::RU]);
+    }
+}
@@ -1,4 +1,19 @@
 mod interpreter;
+mod language;
+mod detector;
+mod backend;
+mod cache;
+mod error;
 mod aliyun_cli;
+mod libre_translate;
+mod openai;
 
-pub use interpreter::Interpreter;
\ No newline at end of file
+pub use interpreter::Interpreter;
+pub use language::{Language, UnknownLanguage};
+pub use detector::detect_language;
+pub use backend::TranslationBackend;
+pub use cache::TranslationCache;
+pub use error::TranslateError;
+pub use aliyun_cli::{AliyunApi, AliyunCli, AliyunConfig};
+pub use libre_translate::LibreTranslate;
+pub use openai::OpenAiCompatible;
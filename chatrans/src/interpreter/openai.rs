@@ -0,0 +1,196 @@
+/**
+ * Reference: https://platform.openai.com/docs/api-reference/chat
+ */
+use async_trait::async_trait;
+use serde_derive::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::interpreter::{Language, TranslateError, TranslationBackend};
+use crate::interpreter::backend::http_client;
+
+const SYSTEM_PROMPT: &str = "\
+You translate in-game chat messages from Korabli (Mir Korabley), a naval battle game, into {language}.
+Players write short, informal messages full of game slang. Follow these rules:
+- Keep ship class shorthand as is: DD (destroyer), CA/CL (cruiser), BB (battleship), CV (aircraft carrier), SS (submarine).
+- Keep capture point names as is, e.g. \"cap B\" means capture point B, \"A\" or \"C\" alone usually refer to points.
+- Keep map grid squares such as \"E5\" or \"F2\" as is.
+- \"spot\" / \"spotted\" mean detecting enemies, \"radar\" and \"hydro\" are detection consumables, \"smoke\" is a smoke screen.
+- \"push\", \"rush\", \"kite\", \"camp\" describe tactics; translate their meaning, not the literal word.
+- Keep ship names, player names, clan tags and numbers untranslated.
+- Keep greetings and emotes such as \"o7\", \"gl hf\", \"gg wp\", \":)\" untouched.
+- Transliterated Russian (e.g. \"privet\") should be translated as Russian.
+Reply with the translation only, without quotes, notes or explanations. If the message is already in {language}, repeat it unchanged.";
+
+#[derive(Serialize)]
+struct ChatMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+#[derive(Serialize)]
+struct ChatCompletionRequest<'a> {
+    model: &'a str,
+    messages: Vec<ChatMessage<'a>>,
+    temperature: f32,
+}
+
+#[derive(Deserialize)]
+struct ChatCompletionResponse {
+    choices: Vec<Choice>,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    message: ChoiceMessage,
+}
+
+#[derive(Deserialize)]
+struct ChoiceMessage {
+    content: String,
+}
+
+/// Client of an OpenAI compatible chat completions server,
+/// such as llama.cpp server, vLLM or Ollama
+pub struct OpenAiCompatible {
+    url: String,
+    model: String,
+    api_key: Option<String>,
+    client: reqwest::Client,
+}
+
+impl OpenAiCompatible {
+    /// `url` is the api base, e.g. `http://localhost:8080/v1`
+    pub fn new(url: String, model: String, api_key: Option<String>) -> OpenAiCompatible {
+        OpenAiCompatible {
+            url: url.trim_end_matches('/').to_string(),
+            model,
+            api_key,
+            client: http_client(),
+        }
+    }
+
+    fn request(&self, builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.api_key {
+            Some(api_key) => builder.bearer_auth(api_key),
+            None => builder,
+        }
+    }
+}
+
+#[async_trait]
+impl TranslationBackend for OpenAiCompatible {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    async fn translate(
+        &self,
+        text: &str,
+        source: Option<&Language>,
+        target: &Language,
+    ) -> Result<String, TranslateError> {
+        let mut system_prompt = SYSTEM_PROMPT.replace("{language}", target.name());
+        if let Some(source) = source {
+            system_prompt.push_str(&format!("\nThe messages are written in {}.", source.name()));
+        }
+        let request = ChatCompletionRequest {
+            model: &self.model,
+            messages: vec![
+                ChatMessage { role: "system", content: &system_prompt },
+                ChatMessage { role: "user", content: text },
+            ],
+            temperature: 0.0,
+        };
+
+        let response = self.request(self.client.post(format!("{}/chat/completions", self.url)))
+            .json(&request)
+            .send()
+            .await?;
+        let status = response.status();
+        let body = response.text().await?;
+        debug!("Chat completions response: {} {}", status, body);
+        if !status.is_success() {
+            return Err(TranslateError::from_status(status, body));
+        }
+
+        let response: ChatCompletionResponse = serde_json::from_str(&body)?;
+        match response.choices.into_iter().next() {
+            Some(choice) => Ok(choice.message.content.trim().to_string()),
+            None => Err(TranslateError::MalformedResponse("No choices in the response".to_string())),
+        }
+    }
+
+    fn supported_languages(&self) -> Vec<Language> {
+        Language::all()
+    }
+
+    async fn health_check(&self) -> Result<(), TranslateError> {
+        let response = self.request(self.client.get(format!("{}/models", self.url)))
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(TranslateError::from_status(status, response.text().await?));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+    use crate::interpreter::backend::mock_endpoint;
+    use super::*;
+
+    fn completion(content: &str) -> String {
+        serde_json::json!({
+            "object": "chat.completion",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": content}, "finish_reason": "stop"}],
+        }).to_string()
+    }
+
+    #[tokio::test]
+    async fn chat_completion_from_mock_endpoint() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1/", listener.local_addr().unwrap());
+        let openai = OpenAiCompatible::new(url, "qwen".to_string(), Some("secret".to_string()));
+
+        let response = completion("\n 推 B，有雷达 \n");
+        let (translated, (head, body)) = tokio::join!(
+            openai.translate("push B, they have radar", Some(&Language::EN), &Language::ZH),
+            mock_endpoint(&listener, "200 OK", &response),
+        );
+        assert_eq!(translated.unwrap(), "推 B，有雷达");
+        assert!(head.starts_with("POST /v1/chat/completions "));
+        assert!(head.lines().any(|line| line.eq_ignore_ascii_case("authorization: Bearer secret")));
+
+        let request: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(request["model"], "qwen");
+        assert_eq!(request["messages"][0]["role"], "system");
+        let prompt = request["messages"][0]["content"].as_str().unwrap();
+        assert!(!prompt.contains("{language}"));
+        assert!(prompt.contains(&format!("into {}.", Language::ZH.name())));
+        assert!(prompt.contains(&format!("already in {}, repeat", Language::ZH.name())));
+        assert!(prompt.ends_with(&format!("\nThe messages are written in {}.", Language::EN.name())));
+        assert_eq!(request["messages"][1]["role"], "user");
+        assert_eq!(request["messages"][1]["content"], "push B, they have radar");
+
+        // Without a source language nor an api key
+        let response = completion("gg");
+        let openai = OpenAiCompatible::new(format!("http://{}/v1", listener.local_addr().unwrap()), "qwen".to_string(), None);
+        let (translated, (head, body)) = tokio::join!(
+            openai.translate("gg", None, &Language::ZH),
+            mock_endpoint(&listener, "200 OK", &response),
+        );
+        assert_eq!(translated.unwrap(), "gg");
+        assert!(!head.to_lowercase().contains("authorization"));
+        let request: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(!request["messages"][0]["content"].as_str().unwrap().contains("The messages are written in"));
+
+        let (translated, _) = tokio::join!(
+            openai.translate("gg", None, &Language::ZH),
+            mock_endpoint(&listener, "200 OK", r#"{"object":"chat.completion","choices":[]}"#),
+        );
+        assert!(matches!(translated, Err(TranslateError::MalformedResponse(_))));
+    }
+}
@@ -1,43 +1,211 @@
 use std::{
     fs::{remove_file, File},
     io::{Read, Seek, SeekFrom},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
-use anyhow::{Result, anyhow};
+use anyhow::Result;
 use notify::{
-    event::{CreateKind, EventKind},
     Config,
+    Event,
     PollWatcher,
+    RecommendedWatcher,
     RecursiveMode,
     Watcher,
 };
-use async_channel::Sender;
-use tokio::time::{Duration, sleep};
+use async_channel::{Receiver, Sender};
+use tokio::time::{Duration, Instant, sleep};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, warn};
 
-use crate::processor::{ChatMessage, ChatLoggerBuilder};
+use crate::live::replay::{BattleTracker, LastReplay, ReplayIdentity};
+use crate::processor::{BattleEvent, BattleMeta, BattleResult, ChatLoggerBuilder};
 
 use replay_parser::{
     ReplayFile,
+    ReplayMeta,
     parse_scripts,
+    analyzer::AnalyzerAdapter,
     packet2::Parser,
 };
 
+/// Default interval between two scans of the replay directory when polling
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Default bounds of the interval between two reads of the replay when no filesystem event arrives.
+/// The interval is the shortest while packets are flowing and doubles up to the longest while the replay is idle.
+const DEFAULT_MIN_READ_INTERVAL: Duration = Duration::from_millis(250);
+const DEFAULT_MAX_READ_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Events of the watcher of the replay directory
+type WatcherEvents = Receiver<notify::Result<Event>>;
+
+/// Why the parsing of a battle stopped
+enum BattleEnd {
+    /// The replay of the battle is not there yet
+    NotStarted,
+    /// `tempArenaInfo.json` is gone, the player left the battle
+    Left,
+    /// The files are of another battle already
+    NextBattle,
+}
+
 pub struct LiveMonitor {
     replay_dir: PathBuf,
-    tx: Sender<ChatMessage>,
+    tx: Sender<BattleEvent>,
+    /// Poll the directory even if filesystem events are available
+    polling: bool,
+    poll_interval: Duration,
+    min_read_interval: Duration,
+    max_read_interval: Duration,
 }
 
 impl LiveMonitor {
-    pub fn new(replay_dir: String, tx: Sender<ChatMessage>) -> LiveMonitor {
+    pub fn new(replay_dir: String, tx: Sender<BattleEvent>) -> LiveMonitor {
         LiveMonitor {
             replay_dir: PathBuf::from(replay_dir),
             tx,
+            polling: false,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            min_read_interval: DEFAULT_MIN_READ_INTERVAL,
+            max_read_interval: DEFAULT_MAX_READ_INTERVAL,
         }
     }
 
-    async fn parse_live_chat(&self) -> Result<()> {
+    /// Poll the replay directory instead of relying on filesystem events
+    pub fn with_polling(mut self, polling: bool) -> LiveMonitor {
+        self.polling = polling;
+        self
+    }
+
+    /// Interval between two scans of the replay directory when polling
+    pub fn with_poll_interval(mut self, interval: Duration) -> LiveMonitor {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Bounds of the interval between two reads of the replay when no filesystem event arrives
+    pub fn with_read_interval(mut self, min: Duration, max: Duration) -> LiveMonitor {
+        self.min_read_interval = min;
+        self.max_read_interval = max.max(min);
+        self
+    }
+
+    /// Whether the path is a Windows drive mounted in WSL,
+    /// where the changes made by Windows programs raise no inotify event
+    fn on_wsl_drive(path: &Path) -> bool {
+        if !cfg!(target_os = "linux") {
+            return false;
+        }
+        let wsl = std::fs::read_to_string("/proc/sys/kernel/osrelease")
+            .is_ok_and(|release| release.to_lowercase().contains("microsoft"));
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        wsl && path.starts_with("/mnt/")
+    }
+
+    /// Watch the replay directory, with filesystem events where they work and polling otherwise.
+    /// The events only wake the monitor up, which then looks at the files itself.
+    fn watch(&self) -> Result<(Box<dyn Watcher + Send>, WatcherEvents)> {
+        let (events_tx, events_rx) = async_channel::bounded(16);
+        let handler = move |event| {
+            // A full channel already wakes the monitor up
+            if let Err(async_channel::TrySendError::Closed(_)) = events_tx.try_send(event) {
+                debug!("Watcher event after the monitor stopped");
+            }
+        };
+
+        if self.polling {
+            info!("Polling the replay directory as requested");
+        } else if Self::on_wsl_drive(&self.replay_dir) {
+            info!("The replay directory is on a Windows drive in WSL, polling it");
+        } else {
+            let watcher = RecommendedWatcher::new(handler.clone(), Config::default())
+                .and_then(|mut watcher| {
+                    watcher.watch(&self.replay_dir, RecursiveMode::NonRecursive)?;
+                    Ok(watcher)
+                });
+            match watcher {
+                Ok(watcher) => {
+                    info!("Watching the replay directory for filesystem events");
+                    return Ok((Box::new(watcher), events_rx));
+                }
+                Err(e) => {
+                    warn!("Filesystem events are not available, polling the replay directory: {:?}", e);
+                }
+            }
+        }
+
+        let mut watcher = PollWatcher::new(handler, Config::default().with_poll_interval(self.poll_interval))?;
+        watcher.watch(&self.replay_dir, RecursiveMode::NonRecursive)?;
+        Ok((Box::new(watcher), events_rx))
+    }
+
+    /// Wait for a change in the replay directory, or at most `timeout`
+    /// in case the event is missed
+    async fn wait_for_change(events: &WatcherEvents, timeout: Duration) {
+        tokio::select! {
+            event = events.recv() => {
+                match event {
+                    Ok(Ok(event)) => debug!("File event: {:?}", event),
+                    Ok(Err(e)) => debug!("Watcher error: {:?}", e),
+                    // The watcher is gone, fall back to the interval
+                    Err(_) => sleep(timeout).await,
+                }
+            }
+            _ = sleep(timeout) => {}
+        }
+        // Several events are taken as one change
+        while events.try_recv().is_ok() {}
+    }
+
+    /// `tempArenaInfo.json` has the format of the metadata in the replay header
+    fn read_meta(info_json: &Path) -> Result<ReplayMeta> {
+        let json = std::fs::read_to_string(info_json)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Why the battle is over, if it is, given the files as they were when it started
+    fn battle_end(
+        temp_replay: &Path,
+        info_json: &Path,
+        date_time: &mut Option<String>,
+        identity: &mut ReplayIdentity,
+        next_battle: Option<u64>,
+    ) -> Option<BattleEnd> {
+        if !info_json.exists() {
+            info!("tempArenaInfo.json not found, waiting for the next game");
+            return Some(BattleEnd::Left);
+        }
+        // Unreadable such as while the game writes it
+        if let Ok(meta) = Self::read_meta(info_json) {
+            match date_time {
+                Some(date_time) if *date_time != meta.dateTime => {
+                    info!("tempArenaInfo.json is of a battle started at {}, parsing it", meta.dateTime);
+                    return Some(BattleEnd::NextBattle);
+                }
+                Some(_) => {}
+                None => *date_time = Some(meta.dateTime),
+            }
+        }
+        match ReplayIdentity::read(temp_replay) {
+            Ok(current) if identity.same_battle(&current) => *identity = current,
+            Ok(_) => {
+                info!("The temp replay has been replaced, parsing the next battle");
+                return Some(BattleEnd::NextBattle);
+            }
+            Err(e) => {
+                info!("Error reading the temp replay, waiting for the next game: {:?}", e);
+                return Some(BattleEnd::Left);
+            }
+        }
+        if let Some(offset) = next_battle {
+            info!("Another arena starts at byte {} of the temp replay, parsing the next battle", offset);
+            return Some(BattleEnd::NextBattle);
+        }
+        None
+    }
+
+    /// Parse the chat of a battle until it is over. `last` is the replay of the last battle parsed,
+    /// which the game may not have replaced yet.
+    async fn parse_live_chat(&self, events: &WatcherEvents, last: &mut Option<LastReplay>) -> Result<BattleEnd> {
         let temp_replay = self.replay_dir.join("temp.korablireplay");
         let info_json = self.replay_dir.join("tempArenaInfo.json");
         info!("Parsing live chat from temp replay: {:?}", temp_replay);
@@ -45,7 +213,23 @@ impl LiveMonitor {
 
         // Check if the file exists
         if !(temp_replay.exists() && info_json.exists()) {
-            return Err(anyhow!("Temp Replay file not found"));
+            debug!("Temp replay not found yet");
+            return Ok(BattleEnd::NotStarted);
+        }
+        let mut identity = ReplayIdentity::read(&temp_replay)?;
+        // Where the battle starts in the replay
+        let mut start = 0;
+        if let Some(last) = last.as_ref().filter(|last| last.identity.same_battle(&identity)) {
+            match last.next_battle {
+                Some(offset) => {
+                    info!("Parsing the next battle from byte {} of the temp replay", offset);
+                    start = offset;
+                }
+                None => {
+                    debug!("The temp replay is still the one of the last battle");
+                    return Ok(BattleEnd::NotStarted);
+                }
+            }
         }
 
         // Get datafiles and specs with the version
@@ -56,99 +240,145 @@ impl LiveMonitor {
 
         debug!("Specs loaded: {}", specs.iter().map(|s| s.name.as_str()).collect::<Vec<&str>>().join(", "));
 
-        // Assign processor and parser
-        let chatlogger = ChatLoggerBuilder::new();
+        // Announce the battle before its chat, with the start time telling it from the next one
+        let mut date_time = None;
+        let meta = match Self::read_meta(&info_json) {
+            Ok(meta) => {
+                info!("Battle on {} ({}), playing {}", meta.mapDisplayName, meta.gameType, meta.playerVehicle);
+                date_time = Some(meta.dateTime.clone());
+                Some(BattleMeta::from(&meta))
+            }
+            Err(e) => {
+                warn!("Error reading the battle metadata from {:?}: {:?}", info_json, e);
+                None
+            }
+        };
+        let players = meta.as_ref().map(|meta| meta.players.clone()).unwrap_or_default();
+        let _ = self.tx.send(BattleEvent::Started(meta)).await;
+
+        // Assign processor and parser, anew for every battle
+        let chatlogger = ChatLoggerBuilder::new().with_players(&players);
         let processor = chatlogger.build(self.tx.clone());
-        let mut analyzer_set = replay_parser::analyzer::AnalyzerAdapter::new(vec![processor]);
+        let mut tracker = BattleTracker::new(AnalyzerAdapter::new(vec![processor]), start);
         let mut p = Parser::new(&specs);
+        let mut ended = false;
 
-        // Monitor the change to the temp.korablireplay file
-        // Open the file
-        let mut file = File::open(&temp_replay)?;
-        // Create a buffer using Vec<u8>
-        let mut buffer: Vec<u8> = Vec::new();
-        // Current file offset
-        let mut offset = 0;
-        loop {
-            // Determine whether to continue
-            if !info_json.exists() {
-                info!("tempArenaInfo.json not found, waiting for the next game");
-                break;
-            }
+        // An error in the middle of the battle still ends it, so that its chat is not sent again
+        let result: Result<BattleEnd> = async {
+            // Monitor the change to the temp.korablireplay file
+            // Open the file
+            let mut file = File::open(&temp_replay)?;
+            // Create a buffer using Vec<u8>
+            let mut buffer: Vec<u8> = Vec::new();
+            // Current file offset
+            let mut offset = start;
+            // Read again soon while the game is writing, back off while it is not
+            let mut read_interval = self.min_read_interval;
+            loop {
+                // Determine whether to continue
+                if let Some(end) = Self::battle_end(
+                    &temp_replay, &info_json, &mut date_time, &mut identity, tracker.state().next_battle,
+                ) {
+                    return Ok(end);
+                }
 
-            // Read the file from the current offset to the end 
-            file.seek(SeekFrom::Start(offset as u64))?;
-            debug!("Offset: {:?}, File size: {:?}", offset, file.metadata()?.len());
-            offset += file.read_to_end(&mut buffer)? as u64;
+                // Read the file from the current offset to the end 
+                file.seek(SeekFrom::Start(offset as u64))?;
+                debug!("Offset: {:?}, File size: {:?}", offset, file.metadata()?.len());
+                let read_bytes = file.read_to_end(&mut buffer)?;
+                offset += read_bytes as u64;
 
-            // Parse the packets
-            let parsed_bytes = p.parse_buffer(&buffer, &mut analyzer_set)? as usize;
-            debug!("Parsed bytes number: {:?}", parsed_bytes);
-            buffer.drain(0..parsed_bytes);
+                // Parse the packets
+                let started = Instant::now();
+                let parsed_bytes = p.parse_buffer(&buffer, &mut tracker)? as usize;
+                buffer.drain(0..parsed_bytes);
 
-            // Sleep for 2 seconds
-            sleep(Duration::from_secs(2)).await;
-        }
+                // Tell the winner as soon as known, the player may stay in the battle long after
+                let state = tracker.state();
+                if state.ended && !ended {
+                    info!("The battle is over, winning team: {:?}", state.winning_team);
+                    let _ = self.tx.send(BattleEvent::Ended(BattleResult {
+                        game_id: state.arena_id,
+                        winning_team: state.winning_team,
+                    })).await;
+                    ended = true;
+                }
 
+                read_interval = if parsed_bytes > 0 {
+                    self.min_read_interval
+                } else {
+                    (read_interval * 2).min(self.max_read_interval)
+                };
+                debug!(
+                    "Read {} bytes, parsed {} bytes in {:?}, next read within {:?}",
+                    read_bytes, parsed_bytes, started.elapsed(), read_interval
+                );
+
+                // Wait for the game to write more
+                Self::wait_for_change(events, read_interval).await;
+            }
+        }.await;
+
+        tracker.finish();
+        let state = tracker.state();
+        if !ended {
+            let _ = self.tx.send(BattleEvent::Ended(BattleResult {
+                game_id: state.arena_id,
+                winning_team: None,
+            })).await;
+        }
+        // A replay holding another arena is parsed again from there,
+        // otherwise it is the last battle until replaced
+        *last = Some(LastReplay { identity, next_battle: state.next_battle });
         info!("Parsing live chat from temp replay done");
 
-        Ok(())
+        result
     }
 
     async fn monitor(&self) -> Result<()> {
         let info_json = self.replay_dir.join("tempArenaInfo.json");
         info!("Parsing live chat from json: {:?}", info_json);
 
+        // Keep the watcher alive as long as the monitor
+        let (_watcher, events) = self.watch()?;
+        // Replay of the last battle parsed
+        let mut last = None;
+        // Last error told to the clients, not repeated while retrying
+        let mut last_error = None;
+
         loop {
-            // Check if the file has been created
+            // Wait for the file to be created
             if !info_json.exists() {
-                // Create a watcher
-                // FIXME: Not work for filesystem in WSL
-                let (watcher_tx, watcher_rx) = async_channel::bounded(1);
-                let mut watcher = PollWatcher::new(
-                    move |res| {
-                        match watcher_tx.send_blocking(res) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                warn!("Error sending watcher event: {:?}", e);
-                            }
-                        }
-                    },
-                    Config::default()
-                        .with_compare_contents(true)
-                        .with_poll_interval(Duration::from_secs(1))
-                )?;
-
-                // Watch the file
-                watcher.watch(
-                    &self.replay_dir,
-                    RecursiveMode::NonRecursive,
-                )?;
-
-                loop {
-                    match watcher_rx.recv().await {
-                        Ok(Ok(event)) => {
-                            debug!("File event: {:?}", event);
-                            // Check if create on the file
-                            if event.kind == EventKind::Create(CreateKind::Any) && event.paths[0] == info_json {
-                                info!("Entering the game");
-                                break;
-                            }
-                        }
-                        _ => {
-                            debug!("Watcher error");
-                        }
-                    }
+                let _ = self.tx.send(BattleEvent::Idle).await;
+                while !info_json.exists() {
+                    Self::wait_for_change(&events, self.max_read_interval).await;
                 }
+                info!("Entering the game");
             }
 
             // Parse live chat
-            match self.parse_live_chat().await {
-                Ok(_) => {
+            let result = self.parse_live_chat(&events, &mut last).await;
+            if result.is_ok() {
+                last_error = None;
+            }
+            match result {
+                Ok(BattleEnd::NotStarted) => {
+                    Self::wait_for_change(&events, self.max_read_interval).await;
+                }
+                Ok(BattleEnd::Left) => {
                     info!("The game has ended, waiting for the next game");
                 }
+                Ok(BattleEnd::NextBattle) => {
+                    info!("The game has ended, entering the next game");
+                }
                 Err(e) => {
                     info!("Error parsing live chat: {:?}", e);
+                    let error = format!("{:#}", e);
+                    if last_error.as_ref() != Some(&error) {
+                        let _ = self.tx.send(BattleEvent::ParseError(error.clone())).await;
+                        last_error = Some(error);
+                    }
+                    Self::wait_for_change(&events, self.max_read_interval).await;
                 }
             }
         }
@@ -181,7 +411,11 @@ impl LiveMonitor {
             tokio::select! {
                 // Using cloned token to listen to cancellation requests
                 _ = token.cancelled() => {}
-                _ = self.monitor() => {}
+                result = self.monitor() => {
+                    if let Err(e) = result {
+                        error!("Live monitor failed: {:?}", e);
+                    }
+                }
             }
         });
 
@@ -198,3 +432,61 @@ impl LiveMonitor {
         info!("Live monitor is stopped");
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn arena_info(date_time: &str) -> String {
+        serde_json::json!({
+            "filtersByShipConfigName": {}, "clientVersionFromExe": "25,5,0", "teamNames": [],
+            "eventType": "", "gameMode": 7, "isObserver": false, "clientVersionFromXml": "25.5.0",
+            "playersPerTeam": 12, "duration": 1200, "gameTypeGameParamId": 0, "playerName": "player",
+            "mapName": "spaces/16_OC_bees_to_honey", "mapBorderName": null, "scenarioConfigId": 0,
+            "teamsCount": 2, "isFogOfWar": 0, "matchGroup": "pvp", "mapDisplayName": "16_OC_bees_to_honey",
+            "tournamentTag": "", "scenarioUiCategoryId": 0, "mapId": 16, "weatherParams": {},
+            "spawnLocations": null, "name": "12x12", "scenario": "Domination", "gameType": "RandomBattle",
+            "dateTime": date_time, "playerID": 0, "disabledShipClasses": [], "playerVehicle": "PJSB018-Yamato",
+            "battleDuration": 1200,
+        }).to_string()
+    }
+
+    #[test]
+    fn tells_the_end_of_the_battle() {
+        let dir = std::env::temp_dir().join(format!("chatrans-live-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let temp_replay = dir.join("temp.korablireplay");
+        let info_json = dir.join("tempArenaInfo.json");
+        std::fs::write(&temp_replay, b"packets").unwrap();
+        std::fs::write(&info_json, arena_info("18.10.2026 20:15:42")).unwrap();
+
+        let mut date_time = None;
+        let mut identity = ReplayIdentity::read(&temp_replay).unwrap();
+        let mut battle_end = |date_time: &mut Option<String>, next_battle| {
+            LiveMonitor::battle_end(&temp_replay, &info_json, date_time, &mut identity, next_battle)
+        };
+
+        // Going on, taking the start time once readable
+        assert!(battle_end(&mut date_time, None).is_none());
+        assert_eq!(date_time.as_deref(), Some("18.10.2026 20:15:42"));
+        std::fs::write(&info_json, b"{\"half written").unwrap();
+        assert!(battle_end(&mut date_time, None).is_none());
+
+        // Another arena in the same replay
+        assert!(matches!(battle_end(&mut date_time, Some(7)), Some(BattleEnd::NextBattle)));
+
+        // Another battle in the metadata
+        std::fs::write(&info_json, arena_info("18.10.2026 20:34:07")).unwrap();
+        assert!(matches!(battle_end(&mut date_time, None), Some(BattleEnd::NextBattle)));
+        assert!(battle_end(&mut None, None).is_none());
+
+        // The replay rewritten
+        std::fs::write(&temp_replay, b"other").unwrap();
+        assert!(matches!(battle_end(&mut None, None), Some(BattleEnd::NextBattle)));
+
+        // Left the battle
+        std::fs::remove_file(&info_json).unwrap();
+        assert!(matches!(battle_end(&mut None, None), Some(BattleEnd::Left)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
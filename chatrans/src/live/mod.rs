@@ -1,3 +1,4 @@
 mod live;
+mod replay;
 
 pub use live::LiveMonitor;
\ No newline at end of file
@@ -0,0 +1,254 @@
+use std::{
+    fs::{File, Metadata},
+    io::Read,
+    path::Path,
+};
+use anyhow::Result;
+use replay_parser::analyzer::AnalyzerAdapter;
+use replay_parser::analyzer::decoder::{DecodedPacket, DecodedPacketPayload};
+use replay_parser::packet2::{Packet, PacketProcessor, PacketType};
+
+/// Size of the magic number, the block count and the length of the metadata JSON
+/// at the beginning of the replay
+const HEADER_SIZE: u64 = 12;
+/// Most bytes of the metadata JSON compared to tell two battles apart
+const MAX_META_SIZE: u64 = 64 * 1024;
+/// Size of the payload size, the type and the clock before the payload of a packet
+const PACKET_HEADER_SIZE: u64 = 12;
+
+/// Identity of the file across renames, the device and inode
+type FileId = (u64, u64);
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// The volume serial number and file index are not available on stable Rust elsewhere,
+/// and the creation time is kept by a file replacing another one of the same name (NTFS tunneling),
+/// so only the metadata in the header tells the battles apart
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<FileId> {
+    None
+}
+
+/// What tells the temp replay of a battle from the one of the next battle,
+/// which the game may write in place or as a new file
+#[derive(Debug, Clone)]
+pub struct ReplayIdentity {
+    file_id: Option<FileId>,
+    /// The header of the replay up to the end of its metadata JSON, holding the start time of the battle
+    head: Vec<u8>,
+    pub size: u64,
+}
+
+impl ReplayIdentity {
+    pub fn read(path: &Path) -> Result<ReplayIdentity> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let mut head = Vec::new();
+        (&file).take(HEADER_SIZE).read_to_end(&mut head)?;
+        if let Some(meta_size) = head.get(8..12) {
+            let meta_size = u32::from_le_bytes(meta_size.try_into()?) as u64;
+            (&file).take(meta_size.min(MAX_META_SIZE)).read_to_end(&mut head)?;
+        }
+        Ok(ReplayIdentity {
+            file_id: file_id(&metadata),
+            head,
+            size: metadata.len(),
+        })
+    }
+
+    /// Whether both are the replay of the same battle, `later` being read after `self`
+    pub fn same_battle(&self, later: &ReplayIdentity) -> bool {
+        let same_file = match (self.file_id, later.file_id) {
+            (Some(id), Some(later_id)) => id == later_id,
+            _ => true,
+        };
+        // The head is shorter while the game has written little
+        let common = self.head.len().min(later.head.len());
+        same_file && later.size >= self.size && self.head[..common] == later.head[..common]
+    }
+}
+
+/// The temp replay parsed last, which the game may not have replaced yet
+#[derive(Debug, Clone)]
+pub struct LastReplay {
+    pub identity: ReplayIdentity,
+    /// Offset of the `Map` packet of the battle following in the same replay, parsed from there
+    pub next_battle: Option<u64>,
+}
+
+/// What the monitor follows of the battle being parsed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BattleState {
+    /// Arena id of the first `Map` packet
+    pub arena_id: Option<i64>,
+    /// Offset of the `Map` packet of another arena, where the next battle starts
+    pub next_battle: Option<u64>,
+    /// Whether the `BattleEnd` packet arrived
+    pub ended: bool,
+    pub winning_team: Option<i64>,
+}
+
+/// Keeps the state of the battle for the monitor and passes its packets on to the analyzers,
+/// up to the `Map` packet of the next battle if the game writes it to the same replay
+pub struct BattleTracker {
+    analyzers: AnalyzerAdapter,
+    state: BattleState,
+    /// Offset of the next packet in the replay
+    offset: u64,
+}
+
+impl BattleTracker {
+    pub fn new(analyzers: AnalyzerAdapter, offset: u64) -> BattleTracker {
+        BattleTracker { analyzers, state: BattleState::default(), offset }
+    }
+
+    pub fn state(&self) -> BattleState {
+        self.state
+    }
+
+    pub fn finish(&mut self) {
+        self.analyzers.finish();
+    }
+}
+
+impl PacketProcessor for BattleTracker {
+    fn process(&mut self, packet: Packet<'_, '_>) {
+        let offset = self.offset;
+        self.offset += PACKET_HEADER_SIZE + packet.packet_size as u64;
+        if self.state.next_battle.is_some() {
+            return;
+        }
+        match &packet.payload {
+            PacketType::Map(map) => match self.state.arena_id {
+                Some(arena_id) if arena_id != map.arena_id => {
+                    self.state.next_battle = Some(offset);
+                    return;
+                }
+                _ => self.state.arena_id = Some(map.arena_id),
+            },
+            // Only decoded when needed, the chat logger decodes every packet already
+            PacketType::EntityMethod(method) if method.method == "onBattleEnd" => {
+                if let DecodedPacketPayload::BattleEnd { winning_team, .. } = DecodedPacket::from(false, &packet).payload {
+                    self.state.ended = true;
+                    self.state.winning_team = Some(winning_team as i64).filter(|team| *team >= 0);
+                }
+            }
+            _ => {}
+        }
+        self.analyzers.process(packet);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, io::Write, rc::Rc};
+    use replay_parser::analyzer::Analyzer;
+    use replay_parser::packet2::Parser;
+    use super::*;
+
+    fn packet(packet_type: u32, payload: &[u8]) -> Vec<u8> {
+        let mut packet = (payload.len() as u32).to_le_bytes().to_vec();
+        packet.extend(packet_type.to_le_bytes());
+        packet.extend(0f32.to_le_bytes());
+        packet.extend(payload);
+        packet
+    }
+
+    fn map_packet(arena_id: i64) -> Vec<u8> {
+        let mut payload = 1u32.to_le_bytes().to_vec();
+        payload.extend(arena_id.to_le_bytes());
+        payload.extend([0; 8 + 128]);
+        payload.extend(4u32.to_le_bytes());
+        payload.extend(b"map1");
+        payload.extend([0; 4 * 4 * 4 + 1]);
+        packet(0x27, &payload)
+    }
+
+    /// Arena ids of the `Map` packets and payloads of the unknown ones passed on
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl Analyzer for Recorder {
+        fn finish(&self) {}
+
+        fn process(&mut self, packet: &Packet<'_, '_>) {
+            let packet = match &packet.payload {
+                PacketType::Map(map) => format!("map {}", map.arena_id),
+                _ => String::from_utf8_lossy(packet.raw).into_owned(),
+            };
+            self.0.borrow_mut().push(packet);
+        }
+    }
+
+    #[test]
+    fn stops_at_the_next_battle() {
+        let first = [map_packet(1), packet(0xFF, b"chat one")].concat();
+        let replay = [first.clone(), map_packet(2), packet(0xFF, b"chat two")].concat();
+        let specs = Vec::new();
+
+        let parse = |start: usize| {
+            let packets = Rc::new(RefCell::new(Vec::new()));
+            let analyzers = AnalyzerAdapter::new(vec![Box::new(Recorder(packets.clone()))]);
+            let mut tracker = BattleTracker::new(analyzers, start as u64);
+            let parsed = Parser::new(&specs).parse_buffer(&replay[start..], &mut tracker).unwrap();
+            assert_eq!(parsed, replay.len() - start);
+            (tracker.state(), packets.take())
+        };
+
+        // The packets of the next battle are left to it
+        let (state, packets) = parse(0);
+        assert_eq!(state.arena_id, Some(1));
+        assert_eq!(state.next_battle, Some(first.len() as u64));
+        assert_eq!(packets, ["map 1", "chat one"]);
+
+        let (state, packets) = parse(first.len());
+        assert_eq!(state.arena_id, Some(2));
+        assert_eq!(state.next_battle, None);
+        assert_eq!(packets, ["map 2", "chat two"]);
+    }
+
+    #[test]
+    fn tells_battles_apart() {
+        let dir = std::env::temp_dir().join(format!("chatrans-replay-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("temp.korablireplay");
+
+        std::fs::write(&path, b"battle one").unwrap();
+        let first = ReplayIdentity::read(&path).unwrap();
+        // Growing
+        File::options().append(true).open(&path).unwrap().write_all(b", more packets").unwrap();
+        let grown = ReplayIdentity::read(&path).unwrap();
+        assert!(first.same_battle(&grown));
+
+        // Rewritten in place, shorter or with another beginning
+        std::fs::write(&path, b"battle").unwrap();
+        assert!(!grown.same_battle(&ReplayIdentity::read(&path).unwrap()));
+        std::fs::write(&path, b"battle two, and its packets").unwrap();
+        assert!(!grown.same_battle(&ReplayIdentity::read(&path).unwrap()));
+
+        // Replaced by another file with the same content
+        let other = dir.join("other.korablireplay");
+        std::fs::write(&other, b"battle one, more packets").unwrap();
+        std::fs::rename(&other, &path).unwrap();
+        let replaced = ReplayIdentity::read(&path).unwrap();
+        assert!(cfg!(not(unix)) || !grown.same_battle(&replaced));
+
+        // Rewritten in place with the same beginning, the start time further in the metadata
+        let replay = |date_time: &str| {
+            let meta = format!(r#"{{"matchGroup": "pvp", "gameMode": 7, "clientVersionFromExe": "25,5,0", "dateTime": "{}"}}"#, date_time);
+            let mut replay = vec![0x12, 0x32, 0x34, 0x11, 1, 0, 0, 0];
+            replay.extend((meta.len() as u32).to_le_bytes());
+            replay.extend(meta.as_bytes());
+            replay.extend(b"packets");
+            replay
+        };
+        std::fs::write(&path, replay("18.10.2026 20:15:42")).unwrap();
+        let first = ReplayIdentity::read(&path).unwrap();
+        std::fs::write(&path, replay("18.10.2026 20:34:07")).unwrap();
+        assert!(!first.same_battle(&ReplayIdentity::read(&path).unwrap()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
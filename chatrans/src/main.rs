@@ -1,12 +1,62 @@
-use clap::Parser;
+use std::{net::IpAddr, num::NonZeroUsize, path::PathBuf, sync::Arc, time::Duration};
+use clap::{CommandFactory, Parser, ValueEnum};
 use tokio::signal;
+use tokio_rustls::rustls::ServerConfig;
 use tokio_util::sync::CancellationToken;
-use tracing::{info, error, Level};
+use tracing::{info, error, warn, Level};
 use tracing_subscriber;
 
+use chatrans::interpreter::{
+    AliyunApi,
+    AliyunCli,
+    AliyunConfig,
+    Interpreter,
+    Language,
+    LibreTranslate,
+    OpenAiCompatible,
+    TranslationBackend,
+    TranslationCache,
+};
 use chatrans::live::LiveMonitor;
-use chatrans::processor::ChatMessage;
-use chatrans::server::WebSocketServer;
+use chatrans::processor::BattleEvent;
+use chatrans::server::{
+    generate_self_signed,
+    load_server_config,
+    Authenticator,
+    ChatStore,
+    MessageFormat,
+    WebSocketServer,
+};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Backend {
+    /// Do not translate the messages
+    None,
+    /// Aliyun Machine Translation, requires the access key id and secret
+    Aliyun,
+    /// A LibreTranslate compatible server, such as a self-hosted instance
+    LibreTranslate,
+    /// An OpenAI compatible chat completions server, such as llama.cpp server, vLLM or Ollama
+    #[value(name = "openai")]
+    OpenAi,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum AliyunApiArg {
+    /// `TranslateECommerce`, used with the `social` scene by default
+    Ecommerce,
+    /// `TranslateGeneral`, used with the `general` scene by default
+    General,
+}
+
+impl From<AliyunApiArg> for AliyunApi {
+    fn from(api: AliyunApiArg) -> Self {
+        match api {
+            AliyunApiArg::Ecommerce => AliyunApi::Ecommerce,
+            AliyunApiArg::General => AliyunApi::General,
+        }
+    }
+}
 
 #[derive(Parser)]
 #[command(
@@ -20,58 +70,312 @@ use chatrans::server::WebSocketServer;
 struct Client {
     #[arg(short, long, help = "The replay dir to use")]
     replay_dir: String,
-    #[arg(short, long, help = "The target language, where `zh` stands for Chinese, `en` stands for English. Default is `zh`", default_value = "zh")]
-    target_language: String,
+    #[arg(long, help = "Poll the replay dir instead of using filesystem events, for filesystems without them. Automatic for Windows drives in WSL")]
+    poll: bool,
+    #[arg(long, value_name = "MS", help = "Milliseconds between two scans of the replay dir when polling", default_value = "1000")]
+    poll_interval: u64,
+    #[arg(long, value_name = "MS", help = "Milliseconds between two reads of the replay while the battle is going on, when no filesystem event arrives", default_value = "250")]
+    min_read_interval: u64,
+    #[arg(long, value_name = "MS", help = "Longest milliseconds between two reads of the replay, the interval doubling up to it while the replay is idle", default_value = "2000")]
+    max_read_interval: u64,
+    #[arg(long, help = "The log level, `debug` adds the read and translation latencies", default_value = "info")]
+    log_level: Level,
+    #[arg(short, long, help = "The default target language as a BCP-47 code, e.g. `zh` for Chinese, `en` for English, `ru` for Russian. Clients may request another one", default_value = "zh")]
+    target_language: Language,
+    #[arg(short, long, help = "The source language as a BCP-47 code. Detected for each message if not given")]
+    source_language: Option<Language>,
     #[arg(short, long, help = "The server ip to use", default_value = "0.0.0.0")]
     ip: String,
     #[arg(short, long, help = "The server port to use", default_value = "38080")]
     port: u16,
+    #[arg(short, long, help = "The default format of the messages sent to the clients, `json` or `text` for the legacy one-line format. Clients may request another one", default_value = "json")]
+    format: MessageFormat,
+    #[arg(long, help = "Number of messages of the current battle replayed to the clients on connect, 0 to disable", default_value = "500")]
+    history_size: usize,
+    #[arg(long, help = "Number of battles kept in memory for the REST API, 0 to disable", default_value = "20")]
+    archive_size: usize,
+    #[arg(short, long, value_enum, help = "The translation backend to use. Default is `aliyun` if the Aliyun access keys are provided, otherwise `none`")]
+    backend: Option<Backend>,
     #[arg(long, help = "The Aliyun access key id")]
     access_key_id: Option<String>,
     #[arg(long, help = "The Aliyun access key secret")]
     access_key_secret: Option<String>,
+    #[arg(long, help = "The Aliyun region", default_value = "cn-hangzhou")]
+    aliyun_region: String,
+    #[arg(long, value_enum, help = "The Aliyun translation api", default_value = "ecommerce")]
+    aliyun_api: AliyunApiArg,
+    #[arg(long, help = "The Aliyun endpoint to use instead of the regional one, e.g. `http://127.0.0.1:8080`")]
+    aliyun_endpoint: Option<String>,
+    #[arg(long, help = "The Aliyun translation scene. Default is `social` for the ecommerce api, `general` for the general api")]
+    aliyun_scene: Option<String>,
+    #[arg(long, help = "The Aliyun source text format, `text` or `html`", default_value = "text")]
+    aliyun_format_type: String,
+    #[arg(long, help = "Translate a word at startup to check the Aliyun access keys, billed as any translation")]
+    aliyun_check: bool,
+    #[arg(long, help = "The LibreTranslate server url", default_value = "http://localhost:5000")]
+    libre_translate_url: String,
+    #[arg(long, help = "The LibreTranslate api key, if the server requires one")]
+    libre_translate_api_key: Option<String>,
+    #[arg(long, help = "The OpenAI compatible api base url", default_value = "http://localhost:8080/v1")]
+    openai_url: String,
+    #[arg(long, help = "The model used for chat completions")]
+    openai_model: Option<String>,
+    #[arg(long, help = "The OpenAI compatible api key, if the server requires one")]
+    openai_api_key: Option<String>,
+    #[arg(long, help = "The number of translations cached in memory, `0` disables the cache", default_value = "1024")]
+    cache_size: usize,
+    #[arg(long, help = "The file to persist the translation cache in, requires a `--cache-size` greater than 0")]
+    cache_file: Option<PathBuf>,
+    #[arg(long, help = "The SQLite database to keep every battle and its chat in")]
+    database: Option<PathBuf>,
+    #[arg(long, help = "The PEM certificate chain to serve `wss://` with", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, help = "The PEM private key of the certificate", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    #[arg(long, help = "Generate a self-signed certificate at `--tls-cert` and `--tls-key` if they do not exist", requires = "tls_cert")]
+    tls_self_signed: bool,
+    #[arg(long, help = "A host name or ip the self-signed certificate is valid for, besides `localhost` and the server ip")]
+    tls_host: Vec<String>,
+    #[arg(long, help = "A token the clients must present, as the `token` query parameter or an `Authorization: Bearer` header")]
+    token: Option<String>,
+    #[arg(long, help = "A file of per-client tokens the clients must present, one `name:token` per line")]
+    token_file: Option<PathBuf>,
 }
 
+impl Client {
+    /// Bounds of the interval between two reads of the replay
+    fn read_interval(&self) -> (Duration, Duration) {
+        if self.min_read_interval > self.max_read_interval {
+            Client::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "`--min-read-interval` must not be greater than `--max-read-interval`",
+                )
+                .exit();
+        }
+        (Duration::from_millis(self.min_read_interval), Duration::from_millis(self.max_read_interval))
+    }
+
+    /// Build the translation backend selected by the arguments
+    fn backend(&self) -> Option<Box<dyn TranslationBackend>> {
+        let keys = match (&self.access_key_id, &self.access_key_secret) {
+            (Some(id), Some(secret)) => Some((id.clone(), secret.clone())),
+            _ => None,
+        };
+        let backend = self.backend.unwrap_or(match keys {
+            Some(_) => Backend::Aliyun,
+            None => Backend::None,
+        });
+
+        match backend {
+            Backend::None => None,
+            Backend::Aliyun => match keys {
+                Some((id, secret)) => Some(Box::new(
+                    AliyunCli::new(id, secret).with_config(AliyunConfig {
+                        region: self.aliyun_region.clone(),
+                        api: self.aliyun_api.into(),
+                        endpoint: self.aliyun_endpoint.clone(),
+                        scene: self.aliyun_scene.clone(),
+                        format_type: self.aliyun_format_type.clone(),
+                        check_translation: self.aliyun_check,
+                    }),
+                )),
+                None => Client::command()
+                    .error(
+                        clap::error::ErrorKind::MissingRequiredArgument,
+                        "the `aliyun` backend requires `--access-key-id` and `--access-key-secret`",
+                    )
+                    .exit(),
+            },
+            Backend::LibreTranslate => Some(Box::new(LibreTranslate::new(
+                self.libre_translate_url.clone(),
+                self.libre_translate_api_key.clone(),
+            ))),
+            Backend::OpenAi => match &self.openai_model {
+                Some(model) => Some(Box::new(OpenAiCompatible::new(
+                    self.openai_url.clone(),
+                    model.clone(),
+                    self.openai_api_key.clone(),
+                ))),
+                None => Client::command()
+                    .error(
+                        clap::error::ErrorKind::MissingRequiredArgument,
+                        "the `openai` backend requires `--openai-model`",
+                    )
+                    .exit(),
+            },
+        }
+    }
+
+    /// Load the TLS configuration, generating a self-signed certificate if asked to
+    fn tls_config(&self) -> Option<Arc<ServerConfig>> {
+        let (cert, key) = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => (cert, key),
+            _ => return None,
+        };
+
+        if self.tls_self_signed && !(cert.exists() && key.exists()) {
+            let mut hosts = vec!["localhost".to_string()];
+            if !self.ip.parse::<IpAddr>().is_ok_and(|ip| ip.is_unspecified()) {
+                hosts.push(self.ip.clone());
+            }
+            hosts.extend(self.tls_host.iter().cloned());
+            if let Err(e) = generate_self_signed(hosts, cert, key) {
+                Client::command()
+                    .error(
+                        clap::error::ErrorKind::Io,
+                        format!("unable to generate the self-signed certificate: {:?}", e),
+                    )
+                    .exit()
+            }
+        }
+
+        match load_server_config(cert, key) {
+            Ok(config) => Some(config),
+            Err(e) => Client::command()
+                .error(
+                    clap::error::ErrorKind::InvalidValue,
+                    format!("unable to load the TLS certificate and key: {:?}", e),
+                )
+                .exit(),
+        }
+    }
+
+    /// Build the authenticator of the clients from the tokens
+    fn auth(&self) -> Authenticator {
+        let mut auth = Authenticator::new();
+        if let Some(token) = &self.token {
+            auth = auth.with_token("shared".to_string(), token.clone());
+        }
+        if let Some(path) = &self.token_file {
+            let tokens = std::fs::read_to_string(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|text| Authenticator::parse_tokens(&text));
+            match tokens {
+                Ok(tokens) => {
+                    for (name, token) in tokens {
+                        auth = auth.with_token(name, token);
+                    }
+                }
+                Err(e) => Client::command()
+                    .error(
+                        clap::error::ErrorKind::InvalidValue,
+                        format!("unable to read the token file {:?}: {}", path, e),
+                    )
+                    .exit(),
+            }
+        }
+        auth
+    }
+
+    /// Open the chat history database, if any
+    fn store(&self) -> Option<ChatStore> {
+        let path = self.database.as_ref()?;
+        match ChatStore::open(path) {
+            Ok(store) => Some(store),
+            Err(e) => Client::command()
+                .error(
+                    clap::error::ErrorKind::Io,
+                    format!("unable to open the database {:?}: {:?}", path, e),
+                )
+                .exit(),
+        }
+    }
+
+    /// Build the translation cache selected by the arguments
+    fn cache(&self) -> Option<TranslationCache> {
+        if self.cache_size == 0 && self.cache_file.is_some() {
+            Client::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "`--cache-file` requires the cache, a `--cache-size` greater than 0",
+                )
+                .exit();
+        }
+        let capacity = NonZeroUsize::new(self.cache_size)?;
+        match TranslationCache::new(capacity, self.cache_file.as_deref()) {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                warn!("Unable to open the translation cache file, caching in memory only: {:?}", e);
+                TranslationCache::new(capacity, None).ok()
+            }
+        }
+    }
+}
 
 fn main() {
+    let client = Client::parse();
+
     let _collector = tracing_subscriber::fmt()
-        .with_max_level(Level::INFO)
+        .with_max_level(client.log_level)
         .init();
 
-    let client = Client::parse();
+    let backend = client.backend();
+    let cache = client.cache();
+    let tls_config = client.tls_config();
+    let auth = client.auth();
+    let store = client.store();
+    let (min_read_interval, max_read_interval) = client.read_interval();
     let input = client.replay_dir;
 
     info!("Parsing live chat from replay dir: {:?}", input);
-    info!("Target language: {:?}", client.target_language);
-    if client.access_key_id.is_some() && client.access_key_secret.is_some() {
-        info!("Aliyun API key provided, using Aliyun API for translation");
-    } else {
-        info!("No Aliyun API key provided, no translation will be done");
+    info!("Default target language: {}", client.target_language);
+    if let Some(source_language) = &client.source_language {
+        info!("Source language: {}", source_language);
+    }
+    match &backend {
+        Some(backend) => {
+            info!("Using `{}` backend for translation", backend.name());
+        }
+        None => {
+            info!("No translation backend selected, no translation will be done");
+        }
     }
     info!("Use `Ctrl+C` to stop the program");
 
-    // Create a channel to pass the chat messages
-    let (tx, rx) = async_channel::bounded::<ChatMessage>(128);
+    // Create a channel to pass the chat messages and rosters
+    let (tx, rx) = async_channel::bounded::<BattleEvent>(128);
 
     // Create CancellationToken
     let token = CancellationToken::new();
 
     // Start the monitor
-    let monitor = LiveMonitor::new(input, tx);
+    let monitor = LiveMonitor::new(input, tx)
+        .with_polling(client.poll)
+        .with_poll_interval(Duration::from_millis(client.poll_interval))
+        .with_read_interval(min_read_interval, max_read_interval);
     let token_clone = token.clone();
     let monitor_thread = std::thread::spawn(move || {
         monitor.run(token_clone);
     });
 
     // Start the websocket server
-    let server = WebSocketServer::new(
+    let mut interpreter = Interpreter::new(client.target_language, backend);
+    if let Some(source_language) = client.source_language {
+        interpreter = interpreter.with_source_language(source_language);
+    }
+    if let Some(cache) = cache {
+        interpreter = interpreter.with_cache(cache);
+    }
+    let mut server = WebSocketServer::new(
         client.ip,
         client.port,
-        client.target_language, 
-        client.access_key_id,
-        client.access_key_secret,
+        interpreter,
         rx,
-    );
+    )
+        .with_format(client.format)
+        .with_history_size(client.history_size)
+        .with_archive_size(client.archive_size);
+    if let Some(tls_config) = tls_config {
+        server = server.with_tls(tls_config);
+    }
+    if let Some(store) = store {
+        server = server.with_store(store);
+    }
+    if auth.is_enabled() {
+        info!("Clients must present a token to connect");
+        server = server.with_auth(auth);
+    }
     let token_clone = token.clone();
     let server_handle = std::thread::spawn(move || {
         server.run(token_clone);
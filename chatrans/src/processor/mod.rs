@@ -1,3 +1,3 @@
 mod processor;
 
-pub use processor::{ChatMessage, ChatLoggerBuilder};
+pub use processor::{ArenaPlayer, BattleEvent, BattleMeta, BattleResult, ChatMessage, ChatLoggerBuilder, Player, Roster};
\ No newline at end of file
@@ -1,42 +1,191 @@
-use replay_parser::analyzer::decoder::{DecodedPacket, DecodedPacketPayload};
+use replay_parser::analyzer::decoder::{DecodedPacket, DecodedPacketPayload, ReceivedPlayer};
 use replay_parser::analyzer::Analyzer;
 use replay_parser::packet2::Packet;
+use replay_parser::ReplayMeta;
 use std::{
     collections::HashMap,
     convert::TryInto,
+    time::{Duration, Instant},
 };
 use async_channel::Sender;
+use serde_derive::Serialize;
 use tracing::debug;
 
+use crate::interpreter::{detect_language, Language};
+
 #[derive(Debug, Clone)]
 pub struct ChatMessage {
     pub clock: f32,
     pub sender: String,
+    /// Clan tag of the sender, empty if not in a clan
+    pub clan: String,
+    pub team: i64,
+    /// Ship id of the sender in the battle
+    pub ship_id: i64,
+    /// Game parameters id of the ship of the sender, `None` if not in `tempArenaInfo.json`
+    pub ship_params_id: Option<u64>,
     pub audience: String,
     pub message: String,
+    /// Language of the message, `None` if it could not be told
+    pub language: Option<Language>,
+    /// Arena id of the battle, `None` before the map is loaded
+    pub game_id: Option<i64>,
+}
+
+/// A player of the battle
+#[derive(Debug, Clone, Serialize)]
+pub struct Player {
+    pub name: String,
+    /// Clan tag, empty if not in a clan
+    pub clan: String,
+    pub team: i64,
+    /// Ship id of the player in the battle
+    pub ship_id: i64,
+}
+
+/// Players of a battle, sent once they are known
+#[derive(Debug, Clone, Serialize)]
+pub struct Roster {
+    /// Arena id of the battle, `None` before the map is loaded
+    pub game_id: Option<i64>,
+    pub players: Vec<Player>,
+}
+
+/// A player as listed in `tempArenaInfo.json`, before the battle is loaded
+#[derive(Debug, Clone, Serialize)]
+pub struct ArenaPlayer {
+    pub name: String,
+    /// 0 for the player, 1 for an ally, 2 for an enemy
+    pub relation: u32,
+    /// Game parameters id of the ship
+    pub ship_params_id: u64,
+    pub account_id: i64,
+}
+
+/// Metadata of a battle, read from `tempArenaInfo.json`
+#[derive(Debug, Clone, Serialize)]
+pub struct BattleMeta {
+    pub map_name: String,
+    pub map_display_name: String,
+    /// Such as `RandomBattle` or `RankedBattle`
+    pub game_type: String,
+    pub scenario: String,
+    /// Local time of the battle, as written by the game
+    pub date_time: String,
+    pub player_name: String,
+    pub player_vehicle: String,
+    pub client_version: String,
+    pub players: Vec<ArenaPlayer>,
 }
 
-pub struct ChatLoggerBuilder;
+impl From<&ReplayMeta> for BattleMeta {
+    fn from(meta: &ReplayMeta) -> BattleMeta {
+        BattleMeta {
+            map_name: meta.mapName.clone(),
+            map_display_name: meta.mapDisplayName.clone(),
+            game_type: meta.gameType.clone(),
+            scenario: meta.scenario.clone(),
+            date_time: meta.dateTime.clone(),
+            player_name: meta.playerName.clone(),
+            player_vehicle: meta.playerVehicle.clone(),
+            client_version: meta.clientVersionFromExe.clone(),
+            players: meta.vehicles
+                .iter()
+                .map(|vehicle| ArenaPlayer {
+                    name: vehicle.name.clone(),
+                    relation: vehicle.relation,
+                    ship_params_id: vehicle.shipId,
+                    account_id: vehicle.id,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Outcome of a battle
+#[derive(Debug, Clone, Serialize)]
+pub struct BattleResult {
+    /// Arena id of the battle, `None` if the map was never loaded
+    pub game_id: Option<i64>,
+    /// `None` for a draw, a battle left before its end or a replay not telling
+    pub winning_team: Option<i64>,
+}
+
+/// What the live monitor and the processor send along while parsing a battle
+#[derive(Debug, Clone)]
+pub enum BattleEvent {
+    /// A new battle started, sent before its other events.
+    /// `None` if `tempArenaInfo.json` could not be read.
+    Started(Option<BattleMeta>),
+    Chat(ChatMessage),
+    Roster(Roster),
+    Ended(BattleResult),
+    /// No battle going on, the monitor waits for the next one
+    Idle,
+    /// The parsing of the battle failed
+    ParseError(String),
+}
+
+pub struct ChatLoggerBuilder {
+    /// Game parameters id of the ship of each player by name
+    ship_params_ids: HashMap<String, u64>,
+}
 
 impl ChatLoggerBuilder {
     pub fn new() -> ChatLoggerBuilder {
-        ChatLoggerBuilder
+        ChatLoggerBuilder {
+            ship_params_ids: HashMap::new(),
+        }
+    }
+
+    /// Tell the ships of the players listed in `tempArenaInfo.json`
+    pub fn with_players(mut self, players: &[ArenaPlayer]) -> ChatLoggerBuilder {
+        self.ship_params_ids = players.iter()
+            .map(|player| (player.name.clone(), player.ship_params_id))
+            .collect();
+        self
     }
 
     pub fn build(
         &self,
-        tx:  Sender<ChatMessage>,
+        tx:  Sender<BattleEvent>,
     ) -> Box<dyn Analyzer> {
         Box::new(ChatLogger {
-            usernames: HashMap::new(),
+            players: HashMap::new(),
+            ship_params_ids: self.ship_params_ids.clone(),
+            arena_id: None,
+            battle_start: None,
             tx,
         })
     }
 }
 
 pub struct ChatLogger {
-    usernames: HashMap<i32, String>,
-    tx: Sender<ChatMessage>,
+    players: HashMap<i32, ReceivedPlayer>,
+    ship_params_ids: HashMap<String, u64>,
+    arena_id: Option<i64>,
+    /// Earliest wall time the battle can have started at, given when its packets were parsed
+    battle_start: Option<Instant>,
+    tx: Sender<BattleEvent>,
+}
+
+impl ChatLogger {
+    /// How long after the game wrote it the packet with the clock is parsed,
+    /// compared with the most timely packet so far.
+    ///
+    /// The packets written before the monitor attached are all parsed at once, each of them
+    /// more timely than the previous ones, so they report no latency: only the packets
+    /// written afterwards measure how late the replay is read.
+    fn latency(&mut self, clock: f32) -> Duration {
+        let now = Instant::now();
+        // Negative, infinite or too large a clock is taken as the start of the battle
+        let start = Duration::try_from_secs_f32(clock).ok()
+            .and_then(|clock| now.checked_sub(clock))
+            .unwrap_or(now);
+        let earliest = self.battle_start.map_or(start, |earliest| earliest.min(start));
+        self.battle_start = Some(earliest);
+        start.duration_since(earliest)
+    }
 }
 
 impl Analyzer for ChatLogger {
@@ -44,6 +193,7 @@ impl Analyzer for ChatLogger {
 
     fn process(&mut self, packet: &Packet<'_, '_>) {
         let decoded = DecodedPacket::from(false, packet);
+        let latency = self.latency(decoded.clock);
         match decoded.payload {
             DecodedPacketPayload::Chat {
                 sender_id,
@@ -52,32 +202,82 @@ impl Analyzer for ChatLogger {
                 ..
             } => {
                 // Chat { entity_id: 409451, sender_id: -1, audience: "battle_prebattle", message: "IDS_OP_01_02_LEEROYY" } ?
-                // If sender_id not in usernames
-                if !self.usernames.contains_key(&sender_id) {
-                    return;
-                }
+                // If sender_id not in players
+                let player = match self.players.get(&sender_id) {
+                    Some(player) => player,
+                    None => return,
+                };
                 debug!(
                     "{}: {}: {} {}",
                     decoded.clock,
-                    self.usernames.get(&sender_id).unwrap(),
+                    player.username,
                     // sender_id,
                     audience,
                     message
                 );
-                let _ = self.tx.send_blocking(ChatMessage {
+                debug!("Latency of the chat message: {:?}", latency);
+                let _ = self.tx.send_blocking(BattleEvent::Chat(ChatMessage {
                     clock: decoded.clock,
-                    sender: self.usernames.get(&sender_id).unwrap().clone(),
+                    sender: player.username.clone(),
+                    clan: player.clan.clone(),
+                    team: player.teamid,
+                    ship_id: player.shipid,
+                    ship_params_id: self.ship_params_ids.get(&player.username).copied(),
                     audience: audience.to_string(),
                     message: message.to_string(),
-                });
+                    language: detect_language(message),
+                    game_id: self.arena_id,
+                }));
+            }
+            DecodedPacketPayload::Map(map) => {
+                self.arena_id = Some(map.arena_id);
             }
             DecodedPacketPayload::EntityInfo { players, .. } => {
                 for player in players.iter() {
-                    self.usernames
-                        .insert(player.playerid.try_into().unwrap(), player.username.clone());
+                    self.players
+                        .insert(player.playerid.try_into().unwrap(), player.clone());
                 }
+                let mut players: Vec<Player> = self.players.values()
+                    .map(|player| Player {
+                        name: player.username.clone(),
+                        clan: player.clan.clone(),
+                        team: player.teamid,
+                        ship_id: player.shipid,
+                    })
+                    .collect();
+                players.sort_by_key(|player| (player.team, player.ship_id));
+                let _ = self.tx.send_blocking(BattleEvent::Roster(Roster {
+                    game_id: self.arena_id,
+                    players,
+                }));
             }
             _ => {}
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn latency_against_most_timely_packet() {
+        let (tx, _rx) = async_channel::unbounded();
+        let mut logger = ChatLogger {
+            players: HashMap::new(),
+            ship_params_ids: HashMap::new(),
+            arena_id: None,
+            battle_start: None,
+            tx,
+        };
+        // Parsed at once, each packet is more timely than the previous ones
+        assert_eq!(logger.latency(10.0), Duration::ZERO);
+        assert_eq!(logger.latency(300.0), Duration::ZERO);
+        // Parsed as if written 300 seconds before
+        assert!(logger.latency(0.0) >= Duration::from_secs(299));
+        // Not a panic on a broken clock
+        for clock in [f32::INFINITY, f32::NAN, -1.0, f32::MAX] {
+            logger.latency(clock);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::sync::Arc;
+use hyper::{StatusCode, Uri, Response};
+use serde::Serialize;
+use serde_derive::Serialize;
+
+use crate::interpreter::{Interpreter, Language};
+use crate::processor::{Player, Roster};
+use crate::server::archive::{Archive, ArchivedBattle};
+use crate::server::http::{self, Body};
+use crate::server::protocol::Chat;
+use crate::server::server::{ProcessedMessage, WebSocketServer};
+use crate::server::store::ChatStore;
+
+/// Number of messages returned by a search by default
+const DEFAULT_SEARCH_LIMIT: usize = 100;
+const MAX_SEARCH_LIMIT: usize = 1000;
+
+#[derive(Serialize)]
+struct BattleSummary {
+    game_id: Option<i64>,
+    started_at: String,
+    players: usize,
+    messages: usize,
+}
+
+#[derive(Serialize)]
+struct BattleChat<'a> {
+    game_id: Option<i64>,
+    started_at: String,
+    roster: &'a [Player],
+    messages: Vec<Chat<'a>>,
+}
+
+#[derive(Serialize)]
+struct SearchResult<'a> {
+    messages: Vec<Chat<'a>>,
+}
+
+#[derive(Serialize)]
+struct ApiError<'a> {
+    error: &'a str,
+}
+
+fn json_response(status: StatusCode, value: &impl Serialize) -> Response<Body> {
+    http::response(status, "application/json", serde_json::to_vec(value).unwrap())
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, &ApiError { error: message })
+}
+
+/// The messages as sent to the WebSocket clients, with the translations into the language
+/// done when they arrived, so that a request does not call the backend for each message
+fn chats(messages: &[Arc<ProcessedMessage>], language: Language) -> Vec<Chat<'_>> {
+    messages.iter()
+        .map(|processed| WebSocketServer::chat(processed, WebSocketServer::stored_translation(processed, language)))
+        .collect()
+}
+
+fn battles(archive: &Archive) -> Response<Body> {
+    let battles: Vec<_> = archive.battles()
+        .iter()
+        .rev()
+        .map(|battle| BattleSummary {
+            game_id: battle.game_id,
+            started_at: battle.started_at.to_rfc3339(),
+            players: battle.roster.len(),
+            messages: battle.messages.len(),
+        })
+        .collect();
+    json_response(StatusCode::OK, &battles)
+}
+
+fn battle_chat(battle: Option<ArchivedBattle>, language: Language) -> Response<Body> {
+    let battle = match battle {
+        Some(battle) => battle,
+        None => return error_response(StatusCode::NOT_FOUND, "Battle not found"),
+    };
+    let chat = BattleChat {
+        game_id: battle.game_id,
+        started_at: battle.started_at.to_rfc3339(),
+        roster: &battle.roster,
+        messages: chats(&battle.messages, language),
+    };
+    json_response(StatusCode::OK, &chat)
+}
+
+/// Read the `player`, `text` and `limit` query parameters of a search
+fn search_parameters(uri: &Uri) -> Result<(Option<String>, Option<String>, usize), String> {
+    let player = WebSocketServer::query_parameter::<String>(uri, "player")?;
+    let text = WebSocketServer::query_parameter::<String>(uri, "text")?;
+    let limit = WebSocketServer::query_parameter::<usize>(uri, "limit")?;
+    if player.is_none() && text.is_none() {
+        return Err("Expected `player` or `text`".to_string());
+    }
+    Ok((player, text, limit.unwrap_or(DEFAULT_SEARCH_LIMIT).min(MAX_SEARCH_LIMIT)))
+}
+
+fn search(archive: &Archive, uri: &Uri, language: Language) -> Response<Body> {
+    let (player, text, limit) = match search_parameters(uri) {
+        Ok(parameters) => parameters,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+    };
+
+    let messages = archive.search(player.as_deref(), text.as_deref(), limit);
+    let result = SearchResult {
+        messages: chats(&messages, language),
+    };
+    json_response(StatusCode::OK, &result)
+}
+
+/// Search the messages of all the battles in the store
+fn history(store: Option<&ChatStore>, uri: &Uri) -> Response<Body> {
+    let store = match store {
+        Some(store) => store,
+        None => return error_response(StatusCode::NOT_FOUND, "No database, see `--database`"),
+    };
+    let (player, text, limit) = match search_parameters(uri) {
+        Ok(parameters) => parameters,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+    };
+    match store.search(player.as_deref(), text.as_deref(), limit) {
+        Ok(messages) => json_response(StatusCode::OK, &messages),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+fn roster(archive: &Archive) -> Response<Body> {
+    match archive.current() {
+        Some(battle) => json_response(StatusCode::OK, &Roster {
+            game_id: battle.game_id,
+            players: battle.roster,
+        }),
+        None => error_response(StatusCode::NOT_FOUND, "No battle yet"),
+    }
+}
+
+/// Answer the requests under `/api`, with the translations of the messages into the `lang` query parameter
+pub async fn handle(uri: &Uri, interpreter: &Interpreter, archive: &Archive, store: Option<&ChatStore>) -> Response<Body> {
+    let language = match WebSocketServer::query_parameter::<Language>(uri, "lang") {
+        Ok(language) => language.unwrap_or(interpreter.language()),
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+    };
+
+    let path: Vec<&str> = uri.path().trim_matches('/').split('/').skip(1).collect();
+    match path.as_slice() {
+        ["battles"] => battles(archive),
+        ["battles", "current"] => battle_chat(archive.current(), language),
+        ["battles", game_id] => match game_id.parse() {
+            Ok(game_id) => battle_chat(archive.get(game_id), language),
+            Err(_) => error_response(StatusCode::BAD_REQUEST, "Invalid battle id"),
+        },
+        ["search"] => search(archive, uri, language),
+        ["history"] => history(store, uri),
+        ["roster"] => roster(archive),
+        _ => error_response(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+use chrono::{DateTime, Utc};
+
+use crate::processor::{Player, Roster};
+use crate::server::server::ProcessedMessage;
+
+/// A battle seen by the server, with its players and chat
+#[derive(Debug, Clone)]
+pub struct ArchivedBattle {
+    /// Arena id of the battle, `None` until the map is loaded
+    pub game_id: Option<i64>,
+    /// When the server saw the battle first
+    pub started_at: DateTime<Utc>,
+    pub roster: Vec<Player>,
+    pub messages: Vec<Arc<ProcessedMessage>>,
+}
+
+/// The last battles and their chat, kept in memory for the REST API
+pub struct Archive {
+    capacity: usize,
+    battles: Mutex<VecDeque<ArchivedBattle>>,
+}
+
+impl Archive {
+    /// Keep up to `capacity` battles, 0 disables the archive
+    pub fn new(capacity: usize) -> Archive {
+        Archive {
+            capacity,
+            battles: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// The battle with the arena id, starting a new one if it is not the last battle.
+    /// A battle without arena id yet is taken as the beginning of the next one.
+    fn battle(battles: &mut VecDeque<ArchivedBattle>, capacity: usize, game_id: Option<i64>) -> &mut ArchivedBattle {
+        let same = match battles.back_mut() {
+            Some(battle) if battle.game_id == game_id => true,
+            Some(battle) if battle.game_id.is_none() => {
+                battle.game_id = game_id;
+                true
+            }
+            _ => false,
+        };
+        if !same {
+            if battles.len() == capacity {
+                battles.pop_front();
+            }
+            battles.push_back(ArchivedBattle {
+                game_id,
+                started_at: Utc::now(),
+                roster: Vec::new(),
+                messages: Vec::new(),
+            });
+        }
+        battles.back_mut().unwrap()
+    }
+
+    pub fn push(&self, message: Arc<ProcessedMessage>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut battles = self.battles.lock().unwrap();
+        Self::battle(&mut battles, self.capacity, message.message.game_id).messages.push(message);
+    }
+
+    pub fn set_roster(&self, roster: Roster) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut battles = self.battles.lock().unwrap();
+        Self::battle(&mut battles, self.capacity, roster.game_id).roster = roster.players;
+    }
+
+    /// All the battles, oldest first
+    pub fn battles(&self) -> Vec<ArchivedBattle> {
+        self.battles.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn get(&self, game_id: i64) -> Option<ArchivedBattle> {
+        self.battles.lock().unwrap()
+            .iter()
+            .find(|battle| battle.game_id == Some(game_id))
+            .cloned()
+    }
+
+    /// The battle going on or the last one
+    pub fn current(&self) -> Option<ArchivedBattle> {
+        self.battles.lock().unwrap().back().cloned()
+    }
+
+    /// The last `limit` messages sent by the player and containing the text,
+    /// in the original or a translation, both compared ignoring the case
+    pub fn search(&self, player: Option<&str>, text: Option<&str>, limit: usize) -> Vec<Arc<ProcessedMessage>> {
+        let player = player.map(str::to_lowercase);
+        let text = text.map(str::to_lowercase);
+        let matches = |processed: &ProcessedMessage| {
+            let message = &processed.message;
+            let player_matches = player.as_ref().is_none_or(|player| message.sender.to_lowercase() == *player);
+            let text_matches = text.as_ref().is_none_or(|text| {
+                message.message.to_lowercase().contains(text)
+                    || processed.translations.values()
+                        .flatten()
+                        .any(|translated| translated.to_lowercase().contains(text))
+            });
+            player_matches && text_matches
+        };
+
+        let battles = self.battles.lock().unwrap();
+        let mut found: Vec<_> = battles.iter()
+            .rev()
+            .flat_map(|battle| battle.messages.iter().rev())
+            .filter(|processed| matches(processed))
+            .take(limit)
+            .cloned()
+            .collect();
+        found.reverse();
+        found
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interpreter::Language;
+
+    fn message(id: u64, game_id: Option<i64>, sender: &str, text: &str) -> Arc<ProcessedMessage> {
+        let mut processed = ProcessedMessage::test(id, game_id, text);
+        processed.message.sender = sender.to_string();
+        processed.translations.insert(Language::ZH, Some(format!("译: {}", text)));
+        Arc::new(processed)
+    }
+
+    fn ids(messages: &[Arc<ProcessedMessage>]) -> Vec<u64> {
+        messages.iter().map(|message| message.id).collect()
+    }
+
+    fn roster(game_id: Option<i64>) -> Roster {
+        Roster {
+            game_id,
+            players: vec![Player { name: "Rorin".to_string(), clan: String::new(), team: 0, ship_id: 409451 }],
+        }
+    }
+
+    #[test]
+    fn battles_by_arena_id() {
+        let archive = Archive::new(2);
+        // The roster received before the map belongs to the battle
+        archive.set_roster(roster(None));
+        archive.push(message(1, Some(1), "Rorin", "gl hf"));
+        assert_eq!(archive.battles().len(), 1);
+        assert_eq!(archive.current().unwrap().roster.len(), 1);
+
+        archive.push(message(2, Some(2), "Yusux", "cap B"));
+        archive.set_roster(roster(Some(3)));
+        archive.push(message(3, Some(3), "Rorin", "gg wp"));
+        let battles = archive.battles();
+        assert_eq!(battles.iter().map(|battle| battle.game_id).collect::<Vec<_>>(), vec![Some(2), Some(3)]);
+        assert!(archive.get(1).is_none());
+        assert_eq!(ids(&archive.get(2).unwrap().messages), vec![2]);
+        assert_eq!(archive.current().unwrap().roster.len(), 1);
+    }
+
+    #[test]
+    fn search() {
+        let archive = Archive::new(4);
+        archive.push(message(1, Some(1), "Rorin", "Cap B please"));
+        archive.push(message(2, Some(1), "Yusux", "cap A"));
+        archive.push(message(3, Some(2), "rorin", "no cap"));
+        archive.push(message(4, Some(2), "Rorin", "gg wp"));
+
+        assert_eq!(ids(&archive.search(Some("RORIN"), None, 10)), vec![1, 3, 4]);
+        assert_eq!(ids(&archive.search(None, Some("cap"), 10)), vec![1, 2, 3]);
+        assert_eq!(ids(&archive.search(Some("rorin"), Some("cap"), 1)), vec![3]);
+        assert_eq!(ids(&archive.search(None, Some("译: gg"), 10)), vec![4]);
+    }
+}
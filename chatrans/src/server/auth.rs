@@ -0,0 +1,257 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use anyhow::{anyhow, Result};
+use thiserror::Error;
+use tokio_tungstenite::tungstenite::http::{header::AUTHORIZATION, HeaderMap, Uri};
+
+/// Number of rejected attempts from an ip before it gets blocked
+const MAX_FAILURES: u32 = 5;
+/// Period over which the rejected attempts are counted
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+/// How long a blocked ip is refused without checking its token
+const BLOCK_DURATION: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Missing token")]
+    Missing,
+    #[error("Invalid token")]
+    Invalid,
+    #[error("Too many rejected attempts, try again later")]
+    Blocked,
+}
+
+/// Rejected attempts of an ip
+struct Failures {
+    count: u32,
+    since: Instant,
+    blocked_until: Option<Instant>,
+}
+
+impl Failures {
+    /// Whether the attempts no longer count nor block the ip
+    fn expired(&self, now: Instant) -> bool {
+        now.duration_since(self.since) > FAILURE_WINDOW
+            && self.blocked_until.is_none_or(|blocked_until| now >= blocked_until)
+    }
+}
+
+/// Checks the tokens of the clients, sent either as an `Authorization: Bearer`
+/// header or as the `token` query parameter.
+/// Every client is accepted if no token is configured.
+#[derive(Default)]
+pub struct Authenticator {
+    /// Client name by token
+    tokens: HashMap<String, String>,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+impl Authenticator {
+    pub fn new() -> Authenticator {
+        Authenticator::default()
+    }
+
+    /// Accept the clients presenting the token, known by the name in the log
+    pub fn with_token(mut self, name: String, token: String) -> Authenticator {
+        self.tokens.insert(token, name);
+        self
+    }
+
+    /// Parse per-client tokens, one `name:token` per line.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn parse_tokens(text: &str) -> Result<Vec<(String, String)>> {
+        text.lines()
+            .enumerate()
+            .map(|(number, line)| (number + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(number, line)| match line.split_once(':') {
+                Some((name, token)) if !name.trim().is_empty() && !token.trim().is_empty() => {
+                    Ok((name.trim().to_string(), token.trim().to_string()))
+                }
+                _ => Err(anyhow!("Line {}: expected `name:token`", number)),
+            })
+            .collect()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// Check the token of a request from the ip, returning the name of the client
+    pub fn authenticate(&self, ip: IpAddr, headers: &HeaderMap, query: Option<&str>) -> Result<Option<&str>, AuthError> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+        if self.is_blocked(ip) {
+            return Err(AuthError::Blocked);
+        }
+
+        let token = match Self::token(headers, query) {
+            Some(token) => token,
+            None => {
+                self.record_failure(ip);
+                return Err(AuthError::Missing);
+            }
+        };
+        // Compare with every token in constant time to not leak how much of it matched
+        let name = self.tokens.iter()
+            .filter(|(known, _)| constant_time_eq(known.as_bytes(), token.as_bytes()))
+            .map(|(_, name)| name.as_str())
+            .last();
+        match name {
+            Some(name) => {
+                self.failures.lock().unwrap().remove(&ip);
+                Ok(Some(name))
+            }
+            None => {
+                self.record_failure(ip);
+                Err(AuthError::Invalid)
+            }
+        }
+    }
+
+    fn token(headers: &HeaderMap, query: Option<&str>) -> Option<String> {
+        let header = headers.get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+        header.or_else(|| {
+            url::form_urlencoded::parse(query?.as_bytes())
+                .find(|(key, _)| key == "token")
+                .map(|(_, token)| token.into_owned())
+        })
+    }
+
+    fn is_blocked(&self, ip: IpAddr) -> bool {
+        let failures = self.failures.lock().unwrap();
+        failures.get(&ip)
+            .and_then(|failures| failures.blocked_until)
+            .is_some_and(|blocked_until| Instant::now() < blocked_until)
+    }
+
+    fn record_failure(&self, ip: IpAddr) {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        // Forget the ips that gave up, so that the map does not grow forever
+        failures.retain(|_, failures| !failures.expired(now));
+        let failures = failures.entry(ip).or_insert(Failures {
+            count: 0,
+            since: now,
+            blocked_until: None,
+        });
+        if now.duration_since(failures.since) > FAILURE_WINDOW {
+            failures.count = 0;
+            failures.since = now;
+            failures.blocked_until = None;
+        }
+        failures.count += 1;
+        if failures.count >= MAX_FAILURES {
+            failures.blocked_until = Some(now + BLOCK_DURATION);
+        }
+    }
+}
+
+/// The path and query of the request for the logs, without the token the query may hold
+pub fn loggable_uri(uri: &Uri) -> String {
+    let query = uri.query().map(|query| {
+        query.split('&')
+            .filter(|pair| !url::form_urlencoded::parse(pair.as_bytes()).any(|(key, _)| key == "token"))
+            .collect::<Vec<_>>()
+            .join("&")
+    });
+    match query {
+        Some(query) if !query.is_empty() => format!("{}?{}", uri.path(), query),
+        _ => uri.path().to_string(),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn disabled_without_tokens() {
+        let auth = Authenticator::new();
+        assert!(matches!(auth.authenticate(IP, &HeaderMap::new(), None), Ok(None)));
+    }
+
+    #[test]
+    fn header_or_query() {
+        let auth = Authenticator::new()
+            .with_token("streamer".to_string(), "s3cret".to_string())
+            .with_token("caller".to_string(), "0ther".to_string());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer s3cret"));
+        assert!(matches!(auth.authenticate(IP, &headers, None), Ok(Some("streamer"))));
+        assert!(matches!(auth.authenticate(IP, &HeaderMap::new(), Some("lang=ru&token=0ther")), Ok(Some("caller"))));
+        assert!(matches!(auth.authenticate(IP, &HeaderMap::new(), Some("token=s3cre")), Err(AuthError::Invalid)));
+        assert!(matches!(auth.authenticate(IP, &HeaderMap::new(), Some("lang=ru")), Err(AuthError::Missing)));
+    }
+
+    #[test]
+    fn blocks_after_failures() {
+        let auth = Authenticator::new().with_token("shared".to_string(), "s3cret".to_string());
+        for _ in 0..MAX_FAILURES {
+            assert!(matches!(auth.authenticate(IP, &HeaderMap::new(), Some("token=guess")), Err(AuthError::Invalid)));
+        }
+        // Even the right token is refused while blocked
+        assert!(matches!(auth.authenticate(IP, &HeaderMap::new(), Some("token=s3cret")), Err(AuthError::Blocked)));
+        let other = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 2));
+        assert!(matches!(auth.authenticate(other, &HeaderMap::new(), Some("token=s3cret")), Ok(Some("shared"))));
+    }
+
+    #[test]
+    fn forgets_expired_failures() {
+        let auth = Authenticator::new().with_token("shared".to_string(), "s3cret".to_string());
+        let other = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 2));
+        assert!(auth.authenticate(IP, &HeaderMap::new(), Some("token=guess")).is_err());
+        assert!(auth.authenticate(other, &HeaderMap::new(), Some("token=guess")).is_err());
+        // The window of the first ip is over, the second one is still blocked
+        let past = Instant::now() - FAILURE_WINDOW * 2;
+        {
+            let mut failures = auth.failures.lock().unwrap();
+            failures.get_mut(&IP).unwrap().since = past;
+            let blocked = failures.get_mut(&other).unwrap();
+            blocked.since = past;
+            blocked.blocked_until = Some(Instant::now() + BLOCK_DURATION);
+        }
+
+        let third = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 3));
+        assert!(auth.authenticate(third, &HeaderMap::new(), Some("token=guess")).is_err());
+        let failures = auth.failures.lock().unwrap();
+        assert!(!failures.contains_key(&IP));
+        assert!(failures.contains_key(&other) && failures.contains_key(&third));
+    }
+
+    #[test]
+    fn parse_token_file() {
+        let tokens = Authenticator::parse_tokens("# team\nstreamer: s3cret\n\ncaller:0ther\n").unwrap();
+        assert_eq!(tokens, vec![
+            ("streamer".to_string(), "s3cret".to_string()),
+            ("caller".to_string(), "0ther".to_string()),
+        ]);
+        assert!(Authenticator::parse_tokens("streamer s3cret").is_err());
+        assert!(Authenticator::parse_tokens(":s3cret").is_err());
+    }
+
+    #[test]
+    fn token_not_logged() {
+        let loggable = |uri: &str| loggable_uri(&uri.parse().unwrap());
+        assert_eq!(loggable("/ws?lang=ru&token=s3cret&format=text"), "/ws?lang=ru&format=text");
+        assert_eq!(loggable("/events?tok%65n=s3cret"), "/events");
+        assert_eq!(loggable("/api/battles?limit=5"), "/api/battles?limit=5");
+        assert_eq!(loggable("/"), "/");
+    }
+}
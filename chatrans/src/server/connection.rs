@@ -0,0 +1,34 @@
+use std::net::SocketAddr;
+
+use crate::interpreter::Language;
+use crate::processor::ChatMessage;
+use crate::server::filter::Filter;
+use crate::server::protocol::MessageFormat;
+
+/// State of a client connection
+#[derive(Debug)]
+pub struct Connection {
+    pub addr: SocketAddr,
+    pub language: Language,
+    pub format: MessageFormat,
+    pub filter: Filter,
+    /// Id of the last chat message sent to the client
+    pub last_id: u64,
+}
+
+impl Connection {
+    pub fn new(addr: SocketAddr, language: Language, format: MessageFormat, filter: Filter) -> Connection {
+        Connection {
+            addr,
+            language,
+            format,
+            filter,
+            last_id: 0,
+        }
+    }
+
+    /// Whether the client wants the message
+    pub fn accepts(&self, message: &ChatMessage) -> bool {
+        self.filter.accepts(message)
+    }
+}
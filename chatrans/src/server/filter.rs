@@ -0,0 +1,107 @@
+use std::collections::BTreeSet;
+use regex::Regex;
+
+use crate::processor::ChatMessage;
+
+/// Audiences of the chat messages sent by the game
+pub const AUDIENCES: [&str; 3] = ["battle_common", "battle_team", "battle_prebattle"];
+
+/// Chat messages a client wants, evaluated on the server.
+/// Each criterion is ignored if `None`, otherwise a message must meet all of them.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub audiences: Option<BTreeSet<String>>,
+    pub teams: Option<BTreeSet<i64>>,
+    pub senders: Option<BTreeSet<String>>,
+    /// Pattern the text of the message must match
+    pub pattern: Option<Regex>,
+}
+
+impl Filter {
+    pub fn accepts(&self, message: &ChatMessage) -> bool {
+        self.audiences.as_ref().is_none_or(|audiences| audiences.contains(&message.audience))
+            && self.teams.as_ref().is_none_or(|teams| teams.contains(&message.team))
+            && self.senders.as_ref().is_none_or(|senders| senders.contains(&message.sender))
+            && self.pattern.as_ref().is_none_or(|pattern| pattern.is_match(&message.message))
+    }
+
+    /// Receive the messages sent to the audiences, in addition to the current ones
+    pub fn subscribe(&mut self, audiences: Vec<String>) {
+        self.audiences.get_or_insert_with(BTreeSet::new).extend(audiences);
+    }
+
+    /// Stop receiving the messages sent to the audiences
+    pub fn unsubscribe(&mut self, audiences: Vec<String>) {
+        let subscribed = self.audiences.get_or_insert_with(|| {
+            AUDIENCES.iter().map(|audience| audience.to_string()).collect()
+        });
+        for audience in audiences {
+            subscribed.remove(&audience);
+        }
+    }
+
+    /// Audiences subscribed to, all of them if `None`
+    pub fn audiences(&self) -> Option<Vec<&str>> {
+        self.audiences.as_ref().map(|audiences| audiences.iter().map(String::as_str).collect())
+    }
+
+    pub fn teams(&self) -> Option<Vec<i64>> {
+        self.teams.as_ref().map(|teams| teams.iter().copied().collect())
+    }
+
+    pub fn senders(&self) -> Option<Vec<&str>> {
+        self.senders.as_ref().map(|senders| senders.iter().map(String::as_str).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::server::ProcessedMessage;
+
+    fn chat_message(audience: &str, team: i64, sender: &str, message: &str) -> ChatMessage {
+        ChatMessage {
+            sender: sender.to_string(),
+            team,
+            audience: audience.to_string(),
+            ..ProcessedMessage::test(0, Some(1), message).message
+        }
+    }
+
+    #[test]
+    fn subscriptions() {
+        let mut filter = Filter::default();
+        assert!(filter.accepts(&chat_message("battle_common", 0, "Rorin", "gl hf")));
+        assert_eq!(filter.audiences(), None);
+
+        filter.subscribe(vec!["battle_team".to_string()]);
+        assert!(filter.accepts(&chat_message("battle_team", 0, "Rorin", "cap B")));
+        assert!(!filter.accepts(&chat_message("battle_common", 0, "Rorin", "gl hf")));
+
+        filter.subscribe(vec!["battle_common".to_string()]);
+        filter.unsubscribe(vec!["battle_team".to_string()]);
+        assert_eq!(filter.audiences(), Some(vec!["battle_common"]));
+    }
+
+    #[test]
+    fn unsubscribe_from_all() {
+        let mut filter = Filter::default();
+        filter.unsubscribe(vec!["battle_prebattle".to_string()]);
+        assert_eq!(filter.audiences(), Some(vec!["battle_common", "battle_team"]));
+        assert!(!filter.accepts(&chat_message("battle_prebattle", 0, "Rorin", "o7")));
+    }
+
+    #[test]
+    fn all_criteria_must_match() {
+        let filter = Filter {
+            audiences: None,
+            teams: Some(BTreeSet::from([1])),
+            senders: Some(BTreeSet::from(["Rorin".to_string(), "Yusux".to_string()])),
+            pattern: Some(Regex::new(r"(?i)\bcap\b").unwrap()),
+        };
+        assert!(filter.accepts(&chat_message("battle_team", 1, "Rorin", "CAP B")));
+        assert!(!filter.accepts(&chat_message("battle_team", 0, "Rorin", "cap B")));
+        assert!(!filter.accepts(&chat_message("battle_team", 1, "Someone", "cap B")));
+        assert!(!filter.accepts(&chat_message("battle_team", 1, "Yusux", "capture")));
+    }
+}
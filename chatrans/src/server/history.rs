@@ -0,0 +1,119 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use crate::server::server::ProcessedMessage;
+
+struct Battle {
+    /// Arena id of the battle the messages belong to
+    game_id: Option<i64>,
+    messages: VecDeque<Arc<ProcessedMessage>>,
+}
+
+/// Ring buffer of the messages of the current battle, replayed to the clients on connect
+pub struct History {
+    capacity: usize,
+    /// Whether to drop the messages of the previous battle when a new one starts
+    per_battle: bool,
+    battle: Mutex<Battle>,
+}
+
+impl History {
+    /// Keep up to `capacity` messages, 0 disables the history
+    pub fn new(capacity: usize) -> History {
+        History {
+            capacity,
+            per_battle: true,
+            battle: Mutex::new(Battle {
+                game_id: None,
+                messages: VecDeque::with_capacity(capacity),
+            }),
+        }
+    }
+
+    /// Keep up to `capacity` messages, whatever battle they belong to
+    pub fn recent(capacity: usize) -> History {
+        History {
+            per_battle: false,
+            ..History::new(capacity)
+        }
+    }
+
+    /// Append a message, dropping the oldest one if full
+    /// and the previous battle if the message belongs to a new one
+    pub fn push(&self, message: Arc<ProcessedMessage>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut battle = self.battle.lock().unwrap();
+        if self.per_battle && battle.game_id != message.message.game_id {
+            battle.game_id = message.message.game_id;
+            battle.messages.clear();
+        }
+        if battle.messages.len() == self.capacity {
+            battle.messages.pop_front();
+        }
+        battle.messages.push_back(message);
+    }
+
+    /// Drop the messages of the previous battle, when a new one starts or the player leaves it
+    pub fn clear(&self) {
+        let mut battle = self.battle.lock().unwrap();
+        battle.game_id = None;
+        battle.messages.clear();
+    }
+
+    /// Messages with an id greater than `since`, all of them if `None`
+    pub fn since(&self, since: Option<u64>) -> Vec<Arc<ProcessedMessage>> {
+        let since = since.unwrap_or_default();
+        self.battle.lock().unwrap().messages
+            .iter()
+            .filter(|message| message.id > since)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(id: u64, game_id: i64) -> Arc<ProcessedMessage> {
+        Arc::new(ProcessedMessage::test(id, Some(game_id), "gl hf"))
+    }
+
+    fn ids(messages: Vec<Arc<ProcessedMessage>>) -> Vec<u64> {
+        messages.iter().map(|message| message.id).collect()
+    }
+
+    #[test]
+    fn ring_buffer_and_cursor() {
+        let history = History::new(3);
+        for id in 1..=4 {
+            history.push(message(id, 1));
+        }
+        assert_eq!(ids(history.since(None)), vec![2, 3, 4]);
+        assert_eq!(ids(history.since(Some(3))), vec![4]);
+        assert!(history.since(Some(4)).is_empty());
+    }
+
+    #[test]
+    fn new_battle_resets() {
+        let history = History::new(3);
+        history.push(message(1, 1));
+        history.push(message(2, 2));
+        assert_eq!(ids(history.since(None)), vec![2]);
+        history.clear();
+        assert!(history.since(None).is_empty());
+
+        let recent = History::recent(3);
+        recent.push(message(1, 1));
+        recent.push(message(2, 2));
+        assert_eq!(ids(recent.since(None)), vec![1, 2]);
+
+        let disabled = History::new(0);
+        disabled.push(message(1, 1));
+        assert!(disabled.since(None).is_empty());
+    }
+}
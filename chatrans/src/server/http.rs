@@ -0,0 +1,121 @@
+use std::convert::Infallible;
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, Full};
+use hyper::{
+    body::Bytes,
+    header::{self, HeaderValue},
+    Method,
+    Request,
+    Response,
+    StatusCode,
+};
+use rust_embed::RustEmbed;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+
+/// Body of the responses, sent at once or streamed
+pub type Body = UnsyncBoxBody<Bytes, Infallible>;
+
+/// The built-in web client, served at `/`
+#[derive(RustEmbed)]
+#[folder = "web/"]
+struct WebClient;
+
+pub fn response(status: StatusCode, content_type: &'static str, body: impl Into<Bytes>) -> Response<Body> {
+    let mut response = Response::new(Full::new(body.into()).boxed_unsync());
+    *response.status_mut() = status;
+    response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+pub fn text_response(status: StatusCode, message: impl Into<String>) -> Response<Body> {
+    response(status, "text/plain; charset=utf-8", message.into())
+}
+
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The file of the web client at the path, if any
+pub fn web_client(path: &str) -> Option<Response<Body>> {
+    let path = match path.trim_start_matches('/') {
+        "" => "index.html",
+        path => path,
+    };
+    let file = WebClient::get(path)?;
+    Some(response(StatusCode::OK, content_type(path), file.data.into_owned()))
+}
+
+/// Whether the request asks to switch to the WebSocket protocol
+pub fn is_websocket_upgrade<B>(request: &Request<B>) -> bool {
+    let contains = |name: header::HeaderName, token: &str| {
+        request.headers()
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    contains(header::CONNECTION, "upgrade") && contains(header::UPGRADE, "websocket")
+}
+
+/// Response accepting the WebSocket upgrade request, see RFC 6455 section 4.2
+pub fn upgrade_response<B>(request: &Request<B>) -> Result<Response<Body>, String> {
+    if request.method() != Method::GET || !is_websocket_upgrade(request) {
+        return Err("Not a WebSocket upgrade request".to_string());
+    }
+    if request.headers().get(header::SEC_WEBSOCKET_VERSION).is_none_or(|version| version != "13") {
+        return Err("Unsupported WebSocket version, expected 13".to_string());
+    }
+    let key = request.headers()
+        .get(header::SEC_WEBSOCKET_KEY)
+        .ok_or("Missing the Sec-WebSocket-Key header")?;
+
+    let mut response = Response::new(Body::default());
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let headers = response.headers_mut();
+    headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(header::SEC_WEBSOCKET_ACCEPT, derive_accept_key(key.as_bytes()).parse().unwrap());
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serves_web_client() {
+        let page = web_client("/").unwrap();
+        assert_eq!(page.headers()[header::CONTENT_TYPE], "text/html; charset=utf-8");
+        let script = web_client("/app.js").unwrap();
+        assert_eq!(script.headers()[header::CONTENT_TYPE], "text/javascript; charset=utf-8");
+        assert!(web_client("/missing.html").is_none());
+    }
+
+    #[test]
+    fn accepts_upgrade() {
+        // Example of the RFC
+        let request = Request::get("/ws")
+            .header(header::CONNECTION, "keep-alive, Upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(())
+            .unwrap();
+        let response = upgrade_response(&request).unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(response.headers()[header::SEC_WEBSOCKET_ACCEPT], "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let request = Request::get("/ws").body(()).unwrap();
+        assert!(!is_websocket_upgrade(&request));
+        assert!(upgrade_response(&request).is_err());
+    }
+}
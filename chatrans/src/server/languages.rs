@@ -0,0 +1,64 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::interpreter::Language;
+
+/// Target languages requested by the connected clients,
+/// each message is translated once per language in the set
+#[derive(Default)]
+pub struct RequestedLanguages {
+    /// Number of clients requesting each language
+    counts: Mutex<HashMap<Language, usize>>,
+}
+
+impl RequestedLanguages {
+    pub fn new() -> RequestedLanguages {
+        RequestedLanguages::default()
+    }
+
+    pub fn add(&self, language: Language) {
+        *self.counts.lock().unwrap().entry(language).or_default() += 1;
+    }
+
+    pub fn remove(&self, language: Language) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&language) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&language);
+            }
+        }
+    }
+
+    /// Move a client from one language to another
+    pub fn replace(&self, old: Language, new: Language) {
+        self.remove(old);
+        self.add(new);
+    }
+
+    pub fn list(&self) -> Vec<Language> {
+        self.counts.lock().unwrap().keys().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts_clients_per_language() {
+        let languages = RequestedLanguages::new();
+        languages.add(Language::ZH);
+        languages.add(Language::ZH);
+        languages.add(Language::EN);
+        languages.replace(Language::EN, Language::RU);
+        let mut list = languages.list();
+        list.sort_by_key(|language| language.code());
+        assert_eq!(list, vec![Language::RU, Language::ZH]);
+
+        languages.remove(Language::ZH);
+        languages.remove(Language::RU);
+        assert_eq!(languages.list(), vec![Language::ZH]);
+        languages.remove(Language::ZH);
+        assert!(languages.list().is_empty());
+    }
+}
@@ -1,3 +1,19 @@
 mod server;
+mod api;
+mod archive;
+mod auth;
+mod connection;
+mod filter;
+mod languages;
+mod history;
+mod http;
+mod protocol;
+mod sse;
+mod store;
+mod tls;
 
-pub use server::WebSocketServer;
\ No newline at end of file
+pub use server::WebSocketServer;
+pub use auth::{AuthError, Authenticator};
+pub use protocol::{MessageFormat, UnknownFormat, PROTOCOL_VERSION};
+pub use store::{ChatStore, StoredMessage};
+pub use tls::{generate_self_signed, load_server_config};
\ No newline at end of file
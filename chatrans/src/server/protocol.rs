@@ -0,0 +1,335 @@
+use std::{fmt, str::FromStr};
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::processor::{BattleMeta, BattleResult};
+
+/// Version of the JSON messages, bumped on incompatible changes
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Error)]
+#[error("Unknown message format `{0}`, expected `json` or `text`")]
+pub struct UnknownFormat(pub String);
+
+/// Format of the chat messages sent to the clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageFormat {
+    /// Versioned JSON envelopes, see [`ServerMessage`]
+    Json,
+    /// Human readable lines, e.g. `[12.00s] name to audience: text |translated|`
+    Text,
+}
+
+impl fmt::Display for MessageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageFormat::Json => f.write_str("json"),
+            MessageFormat::Text => f.write_str("text"),
+        }
+    }
+}
+
+impl FromStr for MessageFormat {
+    type Err = UnknownFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "json" => Ok(MessageFormat::Json),
+            "text" => Ok(MessageFormat::Text),
+            _ => Err(UnknownFormat(s.to_string())),
+        }
+    }
+}
+
+/// Request sent by a client, e.g. `{"type": "ping", "request_id": 1}`
+#[derive(Debug, Deserialize)]
+pub struct ClientRequest {
+    /// Echoed in the response to tell which request it answers
+    #[serde(default)]
+    pub request_id: Option<u64>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+/// Commands the clients can send to the server
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    /// Change the target language, answered with [`ServerMessage::Language`]
+    #[serde(alias = "hello")]
+    SetLanguage {
+        /// Target language as a BCP-47 code
+        language: String,
+    },
+    /// Receive the messages sent to these audiences, answered with [`ServerMessage::Subscriptions`]
+    Subscribe {
+        audiences: Vec<String>,
+    },
+    /// Stop receiving the messages sent to these audiences, answered with [`ServerMessage::Subscriptions`]
+    Unsubscribe {
+        audiences: Vec<String>,
+    },
+    /// Replace the filter of the messages, answered with [`ServerMessage::Filter`].
+    /// Each criterion left out accepts any message.
+    Filter {
+        #[serde(default)]
+        audiences: Option<Vec<String>>,
+        #[serde(default)]
+        teams: Option<Vec<i64>>,
+        #[serde(default)]
+        senders: Option<Vec<String>>,
+        /// Regular expression the text of the messages must match
+        #[serde(default)]
+        pattern: Option<String>,
+    },
+    /// Send the messages of the current battle again, followed by [`ServerMessage::History`].
+    /// The messages only have the translations done when they arrived.
+    History {
+        /// Only the messages with an id greater than this one
+        #[serde(default)]
+        since: Option<u64>,
+    },
+    /// Answered with [`ServerMessage::Pong`]
+    Ping,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Not a JSON request
+    InvalidRequest,
+    UnknownCommand,
+    InvalidLanguage,
+    InvalidFilter,
+}
+
+/// Envelope of the JSON messages sent by the server to the clients
+#[derive(Debug, Serialize)]
+pub struct Envelope<'a> {
+    pub version: u32,
+    /// Id of the request answered by the message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<u64>,
+    #[serde(flatten)]
+    pub message: ServerMessage<'a>,
+}
+
+impl<'a> Envelope<'a> {
+    pub fn new(message: ServerMessage<'a>) -> Envelope<'a> {
+        Envelope {
+            version: PROTOCOL_VERSION,
+            request_id: None,
+            message,
+        }
+    }
+
+    /// Answer to the request with the given id
+    pub fn reply(request_id: Option<u64>, message: ServerMessage<'a>) -> Envelope<'a> {
+        Envelope {
+            request_id,
+            ..Envelope::new(message)
+        }
+    }
+}
+
+/// A chat message sent by a player
+#[derive(Debug, Serialize)]
+pub struct Chat<'a> {
+    /// Sequence number, to be passed as `since` when reconnecting
+    pub id: u64,
+    /// Seconds since the start of the battle
+    pub clock: f32,
+    pub sender: &'a str,
+    pub clan: Option<&'a str>,
+    pub team: i64,
+    pub ship_id: i64,
+    /// Game parameters id of the ship, as in the `players` of `battle_started`
+    pub ship_params_id: Option<u64>,
+    pub audience: &'a str,
+    pub original: &'a str,
+    /// Translation into the language of the client, if any
+    pub translated: Option<&'a str>,
+    /// Detected language of the original message as a BCP-47 code
+    pub language: Option<&'static str>,
+    /// Arena id of the battle
+    pub game_id: Option<i64>,
+}
+
+/// Messages sent by the server to the clients
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage<'a> {
+    Chat(Chat<'a>),
+    /// A new battle started, the messages that follow are of this battle.
+    /// Also sent on connect during a battle, before the history.
+    BattleStarted {
+        /// Metadata of the battle, left out if the game files could not be read
+        #[serde(flatten)]
+        meta: Option<&'a BattleMeta>,
+    },
+    BattleEnded(&'a BattleResult),
+    /// No battle going on
+    Idle,
+    /// The server failed to parse the battle
+    ParseError {
+        message: &'a str,
+    },
+    /// Current target language of the client
+    Language {
+        language: &'static str,
+    },
+    /// Audiences the client is subscribed to, all of them if `None`
+    Subscriptions {
+        audiences: Option<Vec<&'a str>>,
+    },
+    /// Current filter of the client, `None` criteria accept any message
+    Filter {
+        audiences: Option<Vec<&'a str>>,
+        teams: Option<Vec<i64>>,
+        senders: Option<Vec<&'a str>>,
+        pattern: Option<&'a str>,
+    },
+    /// End of the messages sent again for a history request
+    History {
+        count: usize,
+        /// Id of the last message sent, to be passed as `since` later
+        last_id: u64,
+    },
+    Pong,
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_format() {
+        assert_eq!("json".parse::<MessageFormat>().unwrap(), MessageFormat::Json);
+        assert_eq!("Text".parse::<MessageFormat>().unwrap(), MessageFormat::Text);
+        assert!("xml".parse::<MessageFormat>().is_err());
+    }
+
+    #[test]
+    fn chat_envelope() {
+        let envelope = Envelope::new(ServerMessage::Chat(Chat {
+            id: 42,
+            clock: 12.5,
+            sender: "Rorin",
+            clan: None,
+            team: 0,
+            ship_id: 409451,
+            ship_params_id: Some(4181669072),
+            audience: "battle_team",
+            original: "cap B",
+            translated: Some("占领 B 点"),
+            language: Some("en"),
+            game_id: Some(2946124),
+        }));
+        let value = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(value, serde_json::json!({
+            "version": PROTOCOL_VERSION,
+            "type": "chat",
+            "id": 42,
+            "clock": 12.5,
+            "sender": "Rorin",
+            "clan": null,
+            "team": 0,
+            "ship_id": 409451,
+            "ship_params_id": 4181669072u64,
+            "audience": "battle_team",
+            "original": "cap B",
+            "translated": "占领 B 点",
+            "language": "en",
+            "game_id": 2946124,
+        }));
+    }
+
+    #[test]
+    fn lifecycle_envelopes() {
+        let meta = BattleMeta {
+            map_name: "spaces/16_OC_bees_to_honey".to_string(),
+            map_display_name: "Trap".to_string(),
+            game_type: "RandomBattle".to_string(),
+            scenario: "Domination".to_string(),
+            date_time: "18.10.2026 12:00:00".to_string(),
+            player_name: "Rorin".to_string(),
+            player_vehicle: "PASC020-Des-Moines-1948".to_string(),
+            client_version: "25,10,0,0".to_string(),
+            players: Vec::new(),
+        };
+        let value = serde_json::to_value(Envelope::new(ServerMessage::BattleStarted { meta: Some(&meta) })).unwrap();
+        assert_eq!((&value["type"], &value["map_display_name"], &value["players"]), (
+            &serde_json::json!("battle_started"),
+            &serde_json::json!("Trap"),
+            &serde_json::json!([]),
+        ));
+        let value = serde_json::to_value(Envelope::new(ServerMessage::BattleStarted { meta: None })).unwrap();
+        assert_eq!(value, serde_json::json!({"version": PROTOCOL_VERSION, "type": "battle_started"}));
+
+        let result = BattleResult { game_id: Some(2946124), winning_team: Some(1) };
+        assert_eq!(serde_json::to_value(Envelope::new(ServerMessage::BattleEnded(&result))).unwrap(), serde_json::json!({
+            "version": PROTOCOL_VERSION,
+            "type": "battle_ended",
+            "game_id": 2946124,
+            "winning_team": 1,
+        }));
+    }
+
+    #[test]
+    fn parse_commands() {
+        let request: ClientRequest = serde_json::from_str(r#"{"type": "ping", "request_id": 7}"#).unwrap();
+        assert_eq!(request.request_id, Some(7));
+        assert_eq!(request.command, Command::Ping);
+
+        let request: ClientRequest = serde_json::from_str(r#"{"type": "hello", "language": "ru"}"#).unwrap();
+        assert_eq!(request.request_id, None);
+        assert_eq!(request.command, Command::SetLanguage { language: "ru".to_string() });
+
+        let request: ClientRequest = serde_json::from_str(r#"{"type": "subscribe", "audiences": ["battle_team"]}"#).unwrap();
+        assert_eq!(request.command, Command::Subscribe { audiences: vec!["battle_team".to_string()] });
+
+        let request: ClientRequest = serde_json::from_str(r#"{"type": "filter", "teams": [1], "pattern": "cap"}"#).unwrap();
+        assert_eq!(request.command, Command::Filter {
+            audiences: None,
+            teams: Some(vec![1]),
+            senders: None,
+            pattern: Some("cap".to_string()),
+        });
+
+        let request: ClientRequest = serde_json::from_str(r#"{"type": "history"}"#).unwrap();
+        assert_eq!(request.command, Command::History { since: None });
+
+        let request: ClientRequest = serde_json::from_str(r#"{"type": "bye"}"#).unwrap();
+        assert_eq!(request.command, Command::Unknown);
+
+        assert!(serde_json::from_str::<ClientRequest>(r#"{"type": "set_language"}"#).is_err());
+        assert!(serde_json::from_str::<ClientRequest>("ping").is_err());
+    }
+
+    #[test]
+    fn reply_envelope() {
+        let envelope = Envelope::reply(Some(7), ServerMessage::Pong);
+        assert_eq!(serde_json::to_value(&envelope).unwrap(), serde_json::json!({
+            "version": PROTOCOL_VERSION,
+            "request_id": 7,
+            "type": "pong",
+        }));
+
+        let envelope = Envelope::reply(None, ServerMessage::Error {
+            code: ErrorCode::UnknownCommand,
+            message: "Unknown command".to_string(),
+        });
+        assert_eq!(serde_json::to_value(&envelope).unwrap(), serde_json::json!({
+            "version": PROTOCOL_VERSION,
+            "type": "error",
+            "code": "unknown_command",
+            "message": "Unknown command",
+        }));
+    }
+}
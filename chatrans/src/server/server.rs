@@ -1,153 +1,1029 @@
 use std::{
+    collections::{BTreeSet, HashMap},
+    convert::Infallible,
+    fmt::{Debug, Display},
+    future::Future,
     io::Error as IoError,
     net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
 };
-use tokio::net::{TcpListener, TcpStream};
-use futures_util::{SinkExt, StreamExt};
-use tokio_tungstenite::tungstenite::protocol::Message;
-use tokio_util::sync::CancellationToken;
-use tracing::{debug, info, error};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    time::{interval_at, sleep, timeout, Duration, Instant},
+};
+use async_broadcast::{InactiveReceiver, RecvError};
+use hyper::{
+    body::Incoming,
+    server::conn::http1,
+    service::service_fn,
+    upgrade::Upgraded,
+    Method,
+    Request,
+    Response,
+    StatusCode,
+    Uri,
+};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use tokio_rustls::{rustls, TlsAcceptor};
+use regex::Regex;
+use futures_util::{future::join_all, Sink, SinkExt, StreamExt};
+use tokio_tungstenite::{
+    tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, Message, Role},
+    WebSocketStream,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, info, error, warn};
 use anyhow::Result;
 
-use crate::processor::ChatMessage;
-use crate::interpreter::Interpreter;
+use crate::processor::{BattleEvent, BattleResult, ChatMessage};
+use crate::interpreter::{Interpreter, Language};
+use crate::server::api;
+use crate::server::archive::Archive;
+use crate::server::auth::{loggable_uri, AuthError, Authenticator};
+use crate::server::connection::Connection;
+use crate::server::filter::Filter;
+use crate::server::history::History;
+use crate::server::http::{self, Body};
+use crate::server::languages::RequestedLanguages;
+use crate::server::sse;
+use crate::server::store::ChatStore;
+use crate::server::protocol::{
+    Chat,
+    ClientRequest,
+    Command,
+    Envelope,
+    ErrorCode,
+    MessageFormat,
+    ServerMessage,
+};
+
+/// Number of chat messages of the current battle kept for the clients connecting late
+const DEFAULT_HISTORY_SIZE: usize = 500;
+/// Number of messages kept, whatever the battle, for the event streams resuming
+const EVENT_BUFFER_SIZE: usize = 100;
+/// Number of battles kept for the REST API
+const DEFAULT_ARCHIVE_SIZE: usize = 20;
+/// Time allowed for the TLS handshake and to receive the headers of a request
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Interval between two keepalive pings
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+/// Clients silent for longer, not even answering the pings, are disconnected
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time allowed to send a message, so that clients not reading do not block
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time allowed to the connections to close on shutdown
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A chat message with its translation into each requested language
+#[derive(Debug)]
+pub struct ProcessedMessage {
+    /// Sequence number, increasing across battles, starting from 1
+    pub id: u64,
+    pub message: ChatMessage,
+    /// Translations done when the message arrived, `None` if not needed or failed
+    pub translations: HashMap<Language, Option<String>>,
+}
+
+#[cfg(test)]
+impl ProcessedMessage {
+    /// A message of Rorin to the whole battle, without translation, for the tests
+    pub(crate) fn test(id: u64, game_id: Option<i64>, text: &str) -> ProcessedMessage {
+        ProcessedMessage {
+            id,
+            message: ChatMessage {
+                clock: id as f32,
+                sender: "Rorin".to_string(),
+                clan: String::new(),
+                team: 0,
+                ship_id: 409451,
+                ship_params_id: None,
+                audience: "battle_common".to_string(),
+                message: text.to_string(),
+                language: None,
+                game_id,
+            },
+            translations: HashMap::new(),
+        }
+    }
+}
+
+/// What is sent to every connection
+#[derive(Debug, Clone)]
+enum Broadcast {
+    Chat(Arc<ProcessedMessage>),
+    /// The start or end of a battle, the monitor idle or failing
+    Lifecycle(Arc<BattleEvent>),
+}
+
+/// Options requested by a client in the query string
+#[derive(Debug, Default)]
+struct ConnectionOptions {
+    language: Option<Language>,
+    format: Option<MessageFormat>,
+    filter: Filter,
+    /// Id of the last message already received, for reconnecting clients
+    since: Option<u64>,
+}
+
+/// State shared by the connections
+struct ServerState {
+    interpreter: Arc<Interpreter>,
+    auth: Arc<Authenticator>,
+    /// Cancelled when the server shuts down
+    shutdown: CancellationToken,
+    /// Tasks handling the connections, waited for on shutdown
+    connections: TaskTracker,
+    languages: RequestedLanguages,
+    /// Start of the battle going on, sent to the clients connecting late
+    battle: Mutex<Option<Arc<BattleEvent>>>,
+    history: History,
+    /// Last messages, for the event streams resuming after a `Last-Event-ID`
+    recent: History,
+    archive: Archive,
+    store: Option<Arc<ChatStore>>,
+    /// Default format, for clients not requesting one
+    default_format: MessageFormat,
+}
 
 pub struct WebSocketServer {
     ip: String,
     port: u16,
-    langeuage: String,
-    access_key_id: Option<String>,
-    access_key_secret: Option<String>,
-    message_rx: async_channel::Receiver<ChatMessage>,
+    interpreter: Arc<Interpreter>,
+    message_rx: async_channel::Receiver<BattleEvent>,
+    /// Default format, for clients not requesting one
+    format: MessageFormat,
+    history_size: usize,
+    archive_size: usize,
+    store: Option<Arc<ChatStore>>,
+    /// Serve `wss://` if set
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+    auth: Arc<Authenticator>,
+    /// Tasks handling the connections, waited for on shutdown
+    connections: TaskTracker,
 }
 
 impl WebSocketServer {
     pub fn new(
         ip: String,
         port: u16,
-        langeuage: String,
-        access_key_id: Option<String>,
-        access_key_secret: Option<String>,
-        message_rx: async_channel::Receiver<ChatMessage>,
+        interpreter: Interpreter,
+        message_rx: async_channel::Receiver<BattleEvent>,
     ) -> WebSocketServer {
         WebSocketServer {
             ip,
             port,
-            langeuage,
-            access_key_id,
-            access_key_secret,
+            interpreter: Arc::new(interpreter),
             message_rx,
+            format: MessageFormat::Json,
+            history_size: DEFAULT_HISTORY_SIZE,
+            archive_size: DEFAULT_ARCHIVE_SIZE,
+            store: None,
+            tls_config: None,
+            auth: Arc::new(Authenticator::new()),
+            connections: TaskTracker::new(),
         }
     }
 
+    /// Send the messages in the given format to clients not requesting one
+    pub fn with_format(mut self, format: MessageFormat) -> WebSocketServer {
+        self.format = format;
+        self
+    }
+
+    /// Accept the connections over TLS with the configuration, see [`load_server_config`]
+    ///
+    /// [`load_server_config`]: crate::server::load_server_config
+    pub fn with_tls(mut self, tls_config: Arc<rustls::ServerConfig>) -> WebSocketServer {
+        self.tls_config = Some(tls_config);
+        self
+    }
+
+    /// Only accept the clients presenting one of the tokens of the authenticator
+    pub fn with_auth(mut self, auth: Authenticator) -> WebSocketServer {
+        self.auth = Arc::new(auth);
+        self
+    }
+
+    /// Keep up to `history_size` messages of the current battle, 0 disables the history
+    pub fn with_history_size(mut self, history_size: usize) -> WebSocketServer {
+        self.history_size = history_size;
+        self
+    }
+
+    /// Keep up to `archive_size` battles for the REST API, 0 disables the archive
+    pub fn with_archive_size(mut self, archive_size: usize) -> WebSocketServer {
+        self.archive_size = archive_size;
+        self
+    }
+
+    /// Persist the battles and their chat in the store
+    pub fn with_store(mut self, store: ChatStore) -> WebSocketServer {
+        self.store = Some(Arc::new(store));
+        self
+    }
+
+    /// Translate the message once into each of the languages
     async fn process_message(
         interpreter: &Interpreter,
+        id: u64,
         message: ChatMessage,
-    ) -> String {
-        match interpreter.translate(message.message.clone()).await {
-            Some(translated) => {
-                format!(
-                    "[{:4.2}s] {:^20} to {:^20}: {} |{}|",
-                    message.clock,
-                    message.sender,
-                    message.audience,
-                    message.message,
-                    translated,
-                )
-            }
-            None => {
-                format!(
-                    "[{:4.2}s] {:^20} to {:^20}: {}",
-                    message.clock,
-                    message.sender,
-                    message.audience,
-                    message.message,
-                )
+        languages: &[Language],
+    ) -> ProcessedMessage {
+        let translations = join_all(languages.iter().map(|&language| {
+            let message = &message;
+            async move { (language, Self::translate(interpreter, message, language).await) }
+        })).await;
+
+        ProcessedMessage {
+            id,
+            message,
+            translations: translations.into_iter().collect(),
+        }
+    }
+
+    async fn translate(interpreter: &Interpreter, message: &ChatMessage, language: Language) -> Option<String> {
+        match interpreter.translate(&message.message, message.language, language).await {
+            Ok(translated) => translated,
+            Err(e) => {
+                // Deliver the message untranslated rather than dropping it
+                warn!("Failed to translate the message {:?} into `{}`: {}", message.message, language, e);
+                None
+            }
+        }
+    }
+
+    /// Translation of the message into the language, translated now by the interpreter, if given,
+    /// if no client requested the language when the message arrived.
+    /// Without interpreter, only the translations done when the message arrived are used,
+    /// so that sending many messages at once does not call the backend for each.
+    async fn translation(interpreter: Option<&Interpreter>, processed: &ProcessedMessage, language: Language) -> Option<String> {
+        match (processed.translations.get(&language), interpreter) {
+            (Some(translated), _) => translated.clone(),
+            (None, Some(interpreter)) => Self::translate(interpreter, &processed.message, language).await,
+            (None, None) => None,
+        }
+    }
+
+    /// Translation of the message into the language done when the message arrived, if any
+    pub(super) fn stored_translation(processed: &ProcessedMessage, language: Language) -> Option<&str> {
+        processed.translations.get(&language)?.as_deref()
+    }
+
+    pub(super) fn chat<'a>(processed: &'a ProcessedMessage, translated: Option<&'a str>) -> Chat<'a> {
+        let message = &processed.message;
+        Chat {
+            id: processed.id,
+            clock: message.clock,
+            sender: &message.sender,
+            clan: Some(message.clan.as_str()).filter(|clan| !clan.is_empty()),
+            team: message.team,
+            ship_id: message.ship_id,
+            ship_params_id: message.ship_params_id,
+            audience: &message.audience,
+            original: &message.message,
+            translated,
+            language: message.language.map(|language| language.code()),
+            game_id: message.game_id,
+        }
+    }
+
+    fn format_message(processed: &ProcessedMessage, translated: Option<&str>, format: MessageFormat) -> String {
+        let message = &processed.message;
+        match format {
+            MessageFormat::Json => {
+                let envelope = Envelope::new(ServerMessage::Chat(Self::chat(processed, translated)));
+                serde_json::to_string(&envelope).unwrap()
             }
+            MessageFormat::Text => match translated {
+                Some(translated) => {
+                    format!(
+                        "[{:4.2}s] {:^20} to {:^20}: {} |{}|",
+                        message.clock,
+                        message.sender,
+                        message.audience,
+                        message.message,
+                        translated,
+                    )
+                }
+                None => {
+                    format!(
+                        "[{:4.2}s] {:^20} to {:^20}: {}",
+                        message.clock,
+                        message.sender,
+                        message.audience,
+                        message.message,
+                    )
+                }
+            },
+        }
+    }
+
+    /// The change in the battle as sent to the clients, `None` if not sent in the format
+    fn format_lifecycle(event: &BattleEvent, format: MessageFormat) -> Option<String> {
+        match format {
+            MessageFormat::Json => {
+                let message = match event {
+                    BattleEvent::Started(meta) => ServerMessage::BattleStarted { meta: meta.as_ref() },
+                    BattleEvent::Ended(result) => ServerMessage::BattleEnded(result),
+                    BattleEvent::Idle => ServerMessage::Idle,
+                    BattleEvent::ParseError(message) => ServerMessage::ParseError { message },
+                    BattleEvent::Chat(_) | BattleEvent::Roster(_) => return None,
+                };
+                Some(serde_json::to_string(&Envelope::new(message)).unwrap())
+            }
+            MessageFormat::Text => match event {
+                BattleEvent::Started(Some(meta)) => Some(format!("=== {} ({}) ===", meta.map_display_name, meta.game_type)),
+                BattleEvent::Started(None) => Some("=== New battle ===".to_string()),
+                BattleEvent::Ended(BattleResult { winning_team: Some(team), .. }) => {
+                    Some(format!("=== Battle over, team {} won ===", team))
+                }
+                BattleEvent::Ended(_) => Some("=== Battle over ===".to_string()),
+                _ => None,
+            },
+        }
+    }
+
+    /// Name of the event of the stream for the change in the battle
+    fn lifecycle_event_name(event: &BattleEvent) -> &'static str {
+        match event {
+            BattleEvent::Started(_) => "battle_started",
+            BattleEvent::Ended(_) => "battle_ended",
+            BattleEvent::Idle => "idle",
+            BattleEvent::ParseError(_) => "parse_error",
+            BattleEvent::Chat(_) => "chat",
+            BattleEvent::Roster(_) => "roster",
+        }
+    }
+
+    /// Read the raw value of a query parameter of the request, if any
+    fn query_value(uri: &Uri, name: &str) -> Option<String> {
+        let query = uri.query()?;
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    }
+
+    /// Read the value of a query parameter of the request, if any
+    pub(super) fn query_parameter<T>(uri: &Uri, name: &str) -> Result<Option<T>, String>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        Self::query_value(uri, name)
+            .map(|value| value.parse().map_err(|e| format!("Invalid `{}`: {}", name, e)))
+            .transpose()
+    }
+
+    /// Read the comma separated values of a query parameter of the request, if any
+    fn query_list<T>(uri: &Uri, name: &str) -> Result<Option<BTreeSet<T>>, String>
+    where
+        T: FromStr + Ord,
+        T::Err: Display,
+    {
+        Self::query_value(uri, name)
+            .map(|list| {
+                list.split(',')
+                    .map(|value| value.trim().parse().map_err(|e| format!("Invalid `{}`: {}", name, e)))
+                    .collect()
+            })
+            .transpose()
+    }
+
+    /// Read the options requested in the `lang`, `format`, `since` query parameters,
+    /// and the filter in the `audience`, `team`, `sender` and `pattern` ones
+    fn requested_options(uri: &Uri) -> Result<ConnectionOptions, String> {
+        Ok(ConnectionOptions {
+            language: Self::query_parameter(uri, "lang")?,
+            format: Self::query_parameter(uri, "format")?,
+            filter: Filter {
+                audiences: Self::query_list(uri, "audience")?,
+                teams: Self::query_list(uri, "team")?,
+                senders: Self::query_list(uri, "sender")?,
+                pattern: Self::query_parameter(uri, "pattern")?,
+            },
+            since: Self::query_parameter(uri, "since")?,
+        })
+    }
+
+    /// Send a chat message to the client if subscribed to it, translated into its language,
+    /// see [`WebSocketServer::translation`] for the interpreter
+    async fn send_chat<S>(
+        writer: &mut S,
+        interpreter: Option<&Interpreter>,
+        connection: &mut Connection,
+        processed: &ProcessedMessage,
+    ) -> Result<(), S::Error>
+    where
+        S: Sink<Message> + Unpin,
+    {
+        connection.last_id = connection.last_id.max(processed.id);
+        if !connection.accepts(&processed.message) {
+            return Ok(());
         }
+        let translated = Self::translation(interpreter, processed, connection.language).await;
+        let msg = Message::Text(Self::format_message(processed, translated.as_deref(), connection.format));
+        debug!("Sending message: {:?}", msg);
+        writer.send(msg).await
+    }
+
+    /// Send the change in the battle to the client, if sent in its format
+    async fn send_lifecycle<S>(writer: &mut S, connection: &Connection, event: &BattleEvent) -> Result<(), S::Error>
+    where
+        S: Sink<Message> + Unpin,
+    {
+        match Self::format_lifecycle(event, connection.format) {
+            Some(text) => writer.send(Message::Text(text)).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn send_reply<S>(writer: &mut S, request_id: Option<u64>, reply: ServerMessage<'_>) -> Result<(), S::Error>
+    where
+        S: Sink<Message> + Unpin,
+    {
+        let msg = Message::Text(serde_json::to_string(&Envelope::reply(request_id, reply)).unwrap());
+        debug!("Sending reply: {:?}", msg);
+        writer.send(msg).await
+    }
+
+    /// Carry out a command sent by the client and reply to it
+    async fn handle_request<S>(
+        writer: &mut S,
+        state: &ServerState,
+        connection: &mut Connection,
+        text: &str,
+    ) -> Result<(), S::Error>
+    where
+        S: Sink<Message> + Unpin,
+    {
+        let request = match serde_json::from_str::<ClientRequest>(text) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Invalid request from {}: {} ({:?})", connection.addr, e, text);
+                let reply = ServerMessage::Error {
+                    code: ErrorCode::InvalidRequest,
+                    message: e.to_string(),
+                };
+                return Self::send_reply(writer, None, reply).await;
+            }
+        };
+        debug!("Request from {}: {:?}", connection.addr, request);
+
+        let reply = match request.command {
+            Command::SetLanguage { language: code } => match code.parse::<Language>() {
+                Ok(language) => {
+                    info!("WebSocket client {} switched language: {} -> {}", connection.addr, connection.language, language);
+                    state.languages.replace(connection.language, language);
+                    connection.language = language;
+                    ServerMessage::Language { language: language.code() }
+                }
+                Err(e) => ServerMessage::Error {
+                    code: ErrorCode::InvalidLanguage,
+                    message: e.to_string(),
+                },
+            },
+            Command::Subscribe { audiences } => {
+                connection.filter.subscribe(audiences);
+                ServerMessage::Subscriptions { audiences: connection.filter.audiences() }
+            }
+            Command::Unsubscribe { audiences } => {
+                connection.filter.unsubscribe(audiences);
+                ServerMessage::Subscriptions { audiences: connection.filter.audiences() }
+            }
+            Command::Filter { audiences, teams, senders, pattern } => {
+                match pattern.as_deref().map(Regex::new).transpose() {
+                    Ok(pattern) => {
+                        connection.filter = Filter {
+                            audiences: audiences.map(BTreeSet::from_iter),
+                            teams: teams.map(BTreeSet::from_iter),
+                            senders: senders.map(BTreeSet::from_iter),
+                            pattern,
+                        };
+                        let filter = &connection.filter;
+                        ServerMessage::Filter {
+                            audiences: filter.audiences(),
+                            teams: filter.teams(),
+                            senders: filter.senders(),
+                            pattern: filter.pattern.as_ref().map(Regex::as_str),
+                        }
+                    }
+                    Err(e) => ServerMessage::Error {
+                        code: ErrorCode::InvalidFilter,
+                        message: e.to_string(),
+                    },
+                }
+            }
+            Command::History { since } => {
+                let history = state.history.since(since);
+                let mut count = 0;
+                let mut last_id = since.unwrap_or_default();
+                for processed in history {
+                    if connection.accepts(&processed.message) {
+                        count += 1;
+                    }
+                    Self::send_chat(writer, None, connection, &processed).await?;
+                    last_id = processed.id;
+                }
+                ServerMessage::History { count, last_id }
+            }
+            Command::Ping => ServerMessage::Pong,
+            Command::Unknown => ServerMessage::Error {
+                code: ErrorCode::UnknownCommand,
+                message: "Unknown command".to_string(),
+            },
+        };
+        Self::send_reply(writer, request.request_id, reply).await
+    }
+
+    /// Wait for a send to the client, giving up on a client not reading.
+    /// Return whether the send succeeded, logging the error otherwise.
+    async fn sent<F, E>(addr: SocketAddr, what: &str, send: F) -> bool
+    where
+        F: Future<Output = Result<(), E>>,
+        E: Debug,
+    {
+        match timeout(SEND_TIMEOUT, send).await {
+            Ok(Ok(_)) => true,
+            Ok(Err(e)) => {
+                error!("Error sending {} to {}: {:?}", what, addr, e);
+                false
+            }
+            Err(_) => {
+                warn!("Timed out sending {} to {}", what, addr);
+                false
+            }
+        }
+    }
+
+    /// Serve the web client and the WebSocket upgrades over an HTTP connection
+    async fn serve_http<S>(
+        stream: S,
+        addr: SocketAddr,
+        broadcast_rx: InactiveReceiver<Broadcast>,
+        state: Arc<ServerState>,
+    )
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        debug!("Incoming TCP connection from: {}", addr);
+
+        let service = service_fn(|request| {
+            let broadcast_rx = broadcast_rx.clone();
+            let state = state.clone();
+            async move { Ok::<_, Infallible>(Self::route(request, addr, broadcast_rx, state).await) }
+        });
+        let connection = http1::Builder::new()
+            .timer(TokioTimer::new())
+            .header_read_timeout(HANDSHAKE_TIMEOUT)
+            .serve_connection(TokioIo::new(stream), service)
+            .with_upgrades();
+        tokio::pin!(connection);
+
+        let result = tokio::select! {
+            result = connection.as_mut() => result,
+            _ = state.shutdown.cancelled() => {
+                connection.as_mut().graceful_shutdown();
+                connection.await
+            }
+        };
+        if let Err(e) = result {
+            debug!("Error serving HTTP to {}: {}", addr, e);
+        }
+    }
+
+    /// Name of the client presenting a token, or the status and reason rejecting the request
+    fn authenticate(request: &Request<Incoming>, addr: SocketAddr, state: &ServerState) -> Result<Option<String>, (StatusCode, String)> {
+        match state.auth.authenticate(addr.ip(), request.headers(), request.uri().query()) {
+            Ok(name) => Ok(name.map(str::to_string)),
+            Err(e) => {
+                let status = match e {
+                    AuthError::Blocked => {
+                        // Keep the log readable when flooded
+                        debug!("Rejecting request from {}: {}", addr, e);
+                        StatusCode::TOO_MANY_REQUESTS
+                    }
+                    AuthError::Missing | AuthError::Invalid => {
+                        warn!("Rejecting request from {}: {}", addr, e);
+                        StatusCode::UNAUTHORIZED
+                    }
+                };
+                Err((status, e.to_string()))
+            }
+        }
+    }
+
+    async fn route(
+        request: Request<Incoming>,
+        addr: SocketAddr,
+        broadcast_rx: InactiveReceiver<Broadcast>,
+        state: Arc<ServerState>,
+    ) -> Response<Body> {
+        debug!("HTTP request from {}: {} {}", addr, request.method(), loggable_uri(request.uri()));
+        let path = request.uri().path();
+        // Clients of the former versions connect to `/`
+        if path == "/ws" || (path == "/" && http::is_websocket_upgrade(&request)) {
+            return Self::upgrade(request, addr, broadcast_rx, state);
+        }
+        if request.method() != Method::GET && request.method() != Method::HEAD {
+            return http::text_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+        }
+        if path == "/events" {
+            return Self::event_stream(&request, addr, broadcast_rx, state);
+        }
+        if path == "/api" || path.starts_with("/api/") {
+            if let Err((status, reason)) = Self::authenticate(&request, addr, &state) {
+                return http::text_response(status, reason);
+            }
+            return api::handle(request.uri(), &state.interpreter, &state.archive, state.store.as_deref()).await;
+        }
+        match http::web_client(path) {
+            Some(response) => response,
+            None => http::text_response(StatusCode::NOT_FOUND, "Not found"),
+        }
+    }
+
+    /// Check the WebSocket upgrade request, and hand the connection over
+    /// to [`handle_connection`](Self::handle_connection) once accepted
+    fn upgrade(
+        mut request: Request<Incoming>,
+        addr: SocketAddr,
+        broadcast_rx: InactiveReceiver<Broadcast>,
+        state: Arc<ServerState>,
+    ) -> Response<Body> {
+        let response = match http::upgrade_response(&request) {
+            Ok(response) => response,
+            Err(e) => {
+                debug!("Rejecting WebSocket connection from {}: {}", addr, e);
+                return http::text_response(StatusCode::UPGRADE_REQUIRED, e);
+            }
+        };
+        let client = match Self::authenticate(&request, addr, &state) {
+            Ok(client) => client,
+            Err((status, reason)) => return http::text_response(status, reason),
+        };
+        let options = match Self::requested_options(request.uri()) {
+            Ok(options) => options,
+            Err(e) => {
+                warn!("Rejecting WebSocket connection from {}: {}", addr, e);
+                return http::text_response(StatusCode::BAD_REQUEST, e);
+            }
+        };
+
+        // Receive the messages broadcast from now on, the older ones are in the history
+        let broadcast_rx = broadcast_rx.activate();
+        let connections = state.connections.clone();
+        connections.spawn(async move {
+            match hyper::upgrade::on(&mut request).await {
+                Ok(upgraded) => {
+                    let ws_stream = WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None).await;
+                    Self::handle_connection(ws_stream, addr, client, options, broadcast_rx, state).await;
+                }
+                Err(e) => warn!("Error upgrading the connection of {} to WebSocket: {}", addr, e),
+            }
+        });
+        response
+    }
+
+    /// Stream the messages as Server-Sent Events, with the options of the WebSocket clients
+    fn event_stream(
+        request: &Request<Incoming>,
+        addr: SocketAddr,
+        broadcast_rx: InactiveReceiver<Broadcast>,
+        state: Arc<ServerState>,
+    ) -> Response<Body> {
+        if let Err((status, reason)) = Self::authenticate(request, addr, &state) {
+            return http::text_response(status, reason);
+        }
+        let mut options = match Self::requested_options(request.uri()) {
+            Ok(options) => options,
+            Err(e) => {
+                warn!("Rejecting event stream of {}: {}", addr, e);
+                return http::text_response(StatusCode::BAD_REQUEST, e);
+            }
+        };
+        // Sent by the browsers when reconnecting
+        options.since = sse::last_event_id(request.headers()).or(options.since);
+
+        let broadcast_rx = broadcast_rx.activate();
+        let (events_tx, events_rx) = async_channel::bounded(16);
+        let connections = state.connections.clone();
+        connections.spawn(Self::stream_events(events_tx, addr, options, broadcast_rx, state));
+        sse::response(events_rx)
+    }
+
+    /// Send the message as an event if the client accepts it, return whether the client is still there,
+    /// see [`WebSocketServer::translation`] for the interpreter
+    async fn send_event(
+        events: &async_channel::Sender<String>,
+        interpreter: Option<&Interpreter>,
+        connection: &mut Connection,
+        processed: &ProcessedMessage,
+    ) -> bool {
+        connection.last_id = connection.last_id.max(processed.id);
+        if !connection.accepts(&processed.message) {
+            return true;
+        }
+        let translated = Self::translation(interpreter, processed, connection.language).await;
+        let data = Self::format_message(processed, translated.as_deref(), connection.format);
+        matches!(timeout(SEND_TIMEOUT, events.send(sse::event(Some(processed.id), "chat", &data))).await, Ok(Ok(())))
+    }
+
+    /// Send the change in the battle as an event without id, return whether the client is still there
+    async fn send_lifecycle_event(events: &async_channel::Sender<String>, connection: &Connection, event: &BattleEvent) -> bool {
+        let data = match Self::format_lifecycle(event, connection.format) {
+            Some(data) => data,
+            None => return true,
+        };
+        let event = sse::event(None, Self::lifecycle_event_name(event), &data);
+        matches!(timeout(SEND_TIMEOUT, events.send(event)).await, Ok(Ok(())))
+    }
+
+    async fn stream_events(
+        events: async_channel::Sender<String>,
+        addr: SocketAddr,
+        options: ConnectionOptions,
+        mut broadcast_rx: async_broadcast::Receiver<Broadcast>,
+        state: Arc<ServerState>,
+    ) {
+        let mut connection = Connection::new(
+            addr,
+            options.language.unwrap_or(state.interpreter.language()),
+            options.format.unwrap_or(state.default_format),
+            options.filter,
+        );
+        info!("Event stream opened: {}, language: {}, format: {}", addr, connection.language, connection.format);
+        state.languages.add(connection.language);
+
+        // Resume after the last event received, or catch up with the current battle
+        connection.last_id = options.since.unwrap_or_default();
+        let backlog = match options.since {
+            Some(since) => state.recent.since(Some(since)),
+            None => state.history.since(None),
+        };
+        let battle = state.battle.lock().unwrap().clone();
+        let mut open = match battle {
+            Some(battle) => Self::send_lifecycle_event(&events, &connection, &battle).await,
+            None => true,
+        };
+        for processed in backlog {
+            open = Self::send_event(&events, None, &mut connection, &processed).await;
+            if !open {
+                break;
+            }
+        }
+
+        let mut keepalive = interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+        while open {
+            tokio::select! {
+                _ = state.shutdown.cancelled() => break,
+                _ = keepalive.tick() => {
+                    open = matches!(timeout(SEND_TIMEOUT, events.send(sse::comment("ping"))).await, Ok(Ok(())));
+                }
+                msg = broadcast_rx.recv() => match msg {
+                    Ok(Broadcast::Chat(processed)) if processed.id <= connection.last_id => {}
+                    Ok(Broadcast::Chat(processed)) => {
+                        open = Self::send_event(&events, Some(&state.interpreter), &mut connection, &processed).await;
+                    }
+                    Ok(Broadcast::Lifecycle(event)) => {
+                        open = Self::send_lifecycle_event(&events, &connection, &event).await;
+                    }
+                    Err(RecvError::Overflowed(skipped)) => {
+                        warn!("Event stream of {} is too slow, {} messages skipped", addr, skipped);
+                    }
+                    Err(_) => break,
+                },
+            }
+        }
+
+        state.languages.remove(connection.language);
+        info!("Event stream closed: {}", addr);
     }
 
     async fn handle_connection(
-        raw_stream: TcpStream,
+        ws_stream: WebSocketStream<TokioIo<Upgraded>>,
         addr: SocketAddr,
-        mut broadcast_rx: async_broadcast::Receiver<String>,
+        client: Option<String>,
+        options: ConnectionOptions,
+        mut broadcast_rx: async_broadcast::Receiver<Broadcast>,
+        state: Arc<ServerState>,
     ) {
-        info!("WebSocket incoming TCP connection from: {}", addr);
+        let mut connection = Connection::new(
+            addr,
+            options.language.unwrap_or(state.interpreter.language()),
+            options.format.unwrap_or(state.default_format),
+            options.filter,
+        );
+        info!(
+            "WebSocket connection established: {}{}, language: {}, format: {}",
+            addr,
+            client.map(|client| format!(" ({})", client)).unwrap_or_default(),
+            connection.language,
+            connection.format,
+        );
+        state.languages.add(connection.language);
 
-        let ws_stream = tokio_tungstenite::accept_async(raw_stream)
-            .await
-            .expect("Error during the websocket handshake occurred");
-        info!("WebSocket connection established: {}", addr);
+        let (mut writer, mut reader) = ws_stream.split();
 
-        let (mut writer, _reader) = ws_stream.split();
+        // Catch up with the current battle, the messages broadcast meanwhile
+        // may also be in the history and are skipped by their id
+        connection.last_id = options.since.unwrap_or_default();
+        let battle = state.battle.lock().unwrap().clone();
+        if let Some(battle) = battle {
+            let send = Self::send_lifecycle(&mut writer, &connection, &battle);
+            if !Self::sent(addr, "battle", send).await {
+                state.languages.remove(connection.language);
+                return;
+            }
+        }
+        let history = state.history.since(options.since);
+        debug!("Replaying {} messages to {}", history.len(), addr);
+        for processed in history {
+            let send = Self::send_chat(&mut writer, None, &mut connection, &processed);
+            if !Self::sent(addr, "history", send).await {
+                state.languages.remove(connection.language);
+                return;
+            }
+        }
 
+        let mut keepalive = interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+        let mut last_seen = Instant::now();
         loop {
-            let msg = broadcast_rx.recv().await;
-            debug!("Received broadcast message: {:?}", msg);
-            match msg {
-                Ok(msg) => {
-                    let msg = Message::Text(msg);
-                    debug!("Sending message: {:?}", msg);
-                    match writer.send(msg).await {
-                        Ok(_) => {
+            tokio::select! {
+                _ = state.shutdown.cancelled() => {
+                    let close = Message::Close(Some(CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "Server is shutting down".into(),
+                    }));
+                    Self::sent(addr, "close frame", writer.send(close)).await;
+                    break;
+                }
+                _ = keepalive.tick() => {
+                    if last_seen.elapsed() > IDLE_TIMEOUT {
+                        warn!("WebSocket client {} is not responding, disconnecting", addr);
+                        break;
+                    }
+                    if !Self::sent(addr, "ping", writer.send(Message::Ping(Vec::new()))).await {
+                        break;
+                    }
+                }
+                msg = broadcast_rx.recv() => {
+                    debug!("Received broadcast message: {:?}", msg);
+                    match msg {
+                        Ok(Broadcast::Chat(processed)) if processed.id <= connection.last_id => {}
+                        Ok(Broadcast::Chat(processed)) => {
+                            let send = Self::send_chat(&mut writer, Some(&state.interpreter), &mut connection, &processed);
+                            if !Self::sent(addr, "message", send).await {
+                                break;
+                            }
                             debug!("Sent message successfully");
                         }
+                        Ok(Broadcast::Lifecycle(event)) => {
+                            let send = Self::send_lifecycle(&mut writer, &connection, &event);
+                            if !Self::sent(addr, "battle event", send).await {
+                                break;
+                            }
+                        }
+                        Err(RecvError::Overflowed(skipped)) => {
+                            warn!("WebSocket client {} is too slow, {} messages skipped", addr, skipped);
+                        }
                         Err(e) => {
-                            error!("Error sending message when handling connection: {:?}", e);
+                            error!("Error receiving message when handling connection: {:?}", e);
                             break;
                         }
                     }
                 }
-                Err(e) => {
-                    error!("Error receiving message when handling connection: {:?}", e);
-                    break;
+                msg = reader.next() => {
+                    last_seen = Instant::now();
+                    match msg {
+                        Some(Ok(Message::Text(text))) => {
+                            let reply = Self::handle_request(&mut writer, &state, &mut connection, &text);
+                            if !Self::sent(addr, "reply", reply).await {
+                                break;
+                            }
+                        }
+                        Some(Ok(Message::Close(_))) | None => {
+                            break;
+                        }
+                        Some(Ok(_)) => {}
+                        Some(Err(e)) => {
+                            error!("Error reading message when handling connection: {:?}", e);
+                            break;
+                        }
+                    }
                 }
             }
         }
 
+        state.languages.remove(connection.language);
+        // Complete the closing handshake, if the client is still there
+        let _ = timeout(CLOSE_TIMEOUT, writer.close()).await;
         info!("WebSocket connection closed: {}", addr);
     }
 
-    async fn server(
-        ip: String,
-        port: u16,
-        langeuage: String,
-        access_key_id: Option<String>,
-        access_key_secret: Option<String>,
-        message_rx: async_channel::Receiver<ChatMessage>,
-    ) -> Result<(), IoError> {
-        let addr = format!("{}:{}", ip, port);
+    /// Send the message to every connection, if any
+    async fn broadcast(broadcast_tx: &async_broadcast::Sender<Broadcast>, message: Broadcast) {
+        if broadcast_tx.receiver_count() == 0 {
+            debug!("No client to broadcast the message to: {:?}", message);
+            return;
+        }
+        debug!("Broadcasting message: {:?}", message);
+        match broadcast_tx.broadcast(message).await {
+            Ok(ok) => {
+                debug!("Broadcasted message: {:?}, listeners: {}", ok, broadcast_tx.receiver_count());
+            }
+            Err(e) => {
+                error!("Error broadcasting message: {:?}", e);
+            }
+        }
+    }
+
+    async fn server(&self, token: CancellationToken) -> Result<(), IoError> {
+        let addr = format!("{}:{}", self.ip, self.port);
+        let interpreter = self.interpreter.clone();
+        let message_rx = self.message_rx.clone();
+        let tls_acceptor = self.tls_config.clone().map(TlsAcceptor::from);
 
         // Create the event loop and TCP listener we'll accept connections on.
-        let try_socket = TcpListener::bind(&addr).await;
-        let listener = try_socket.expect("Failed to bind");
-        info!("Listening on: {}", addr);
-
-        // Spawn a task to listen for incoming messages and broadcast them
-        let (broadcast_tx, broadcast_rx) = async_broadcast::broadcast(128);
-        let interpreter = Interpreter::new(
-            langeuage,
-            access_key_id,
-            access_key_secret,
-        );
+        let listener = match TcpListener::bind(&addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to bind {}: {}", addr, e);
+                return Err(e);
+            }
+        };
+        info!("Listening on: {}{}", addr, if tls_acceptor.is_some() { " (TLS)" } else { "" });
+
+        // Spawn a task to listen for incoming messages and broadcast them.
+        // Slow clients miss the oldest messages rather than holding back the others.
+        let (mut broadcast_tx, broadcast_rx) = async_broadcast::broadcast(128);
+        broadcast_tx.set_overflow(true);
+        let broadcast_rx = broadcast_rx.deactivate();
+        interpreter.health_check().await;
+        let state = Arc::new(ServerState {
+            interpreter,
+            auth: self.auth.clone(),
+            shutdown: token.clone(),
+            connections: self.connections.clone(),
+            languages: RequestedLanguages::new(),
+            battle: Mutex::new(None),
+            history: History::new(self.history_size),
+            recent: History::recent(EVENT_BUFFER_SIZE),
+            archive: Archive::new(self.archive_size),
+            store: self.store.clone(),
+            default_format: self.format,
+        });
+        let repeater_state = state.clone();
+        // Messages kept to be read later are translated even when no client is connected
+        let keeps_messages = self.store.is_some() || self.archive_size > 0;
         let message_repeater = tokio::spawn(async move {
+            let state = repeater_state;
+            let mut id = 0;
             loop {
                 debug!("Waiting for message");
                 let message = message_rx.recv().await;
                 match message {
-                    Ok(msg) => {
-                        let msg = Self::process_message(&interpreter, msg).await;
-                        debug!("Broadcasting message: {:?}", msg);
-                        match broadcast_tx.broadcast(msg).await {
-                            Ok(ok) => {
-                                debug!("Broadcasted message: {:?}, listeners: {}", ok, broadcast_tx.receiver_count());
-                            }
-                            Err(e) => {
-                                error!("Error broadcasting message: {:?}", e);
-                            }
+                    Ok(BattleEvent::Started(meta)) => {
+                        if let Some(meta) = meta.clone() {
+                            Self::store(&state.store, "battle", move |store| store.start_battle(&meta)).await;
+                        }
+                        let event = Arc::new(BattleEvent::Started(meta));
+                        state.history.clear();
+                        *state.battle.lock().unwrap() = Some(event.clone());
+                        Self::broadcast(&broadcast_tx, Broadcast::Lifecycle(event)).await;
+                    }
+                    Ok(event @ (BattleEvent::Ended(_) | BattleEvent::Idle | BattleEvent::ParseError(_))) => {
+                        if let BattleEvent::Idle = event {
+                            state.history.clear();
+                            *state.battle.lock().unwrap() = None;
+                        }
+                        Self::broadcast(&broadcast_tx, Broadcast::Lifecycle(Arc::new(event))).await;
+                    }
+                    Ok(BattleEvent::Roster(roster)) => {
+                        debug!("Roster of the battle {:?}: {} players", roster.game_id, roster.players.len());
+                        let stored = roster.clone();
+                        Self::store(&state.store, "roster", move |store| store.set_roster(&stored)).await;
+                        state.archive.set_roster(roster);
+                    }
+                    Ok(BattleEvent::Chat(msg)) => {
+                        id += 1;
+                        let started = Instant::now();
+                        let mut languages = state.languages.list();
+                        if keeps_messages && !languages.contains(&state.interpreter.language()) {
+                            languages.push(state.interpreter.language());
                         }
+                        let msg = Self::process_message(&state.interpreter, id, msg, &languages).await;
+                        debug!("Processed message {} in {:?}", id, started.elapsed());
+                        let msg = Arc::new(msg);
+                        state.history.push(msg.clone());
+                        state.recent.push(msg.clone());
+                        state.archive.push(msg.clone());
+                        let stored = msg.clone();
+                        Self::store(&state.store, "message", move |store| store.insert(&stored)).await;
+                        Self::broadcast(&broadcast_tx, Broadcast::Chat(msg)).await;
                     }
-                    _ => {
+                    Err(_) => {
                         break;
                     }
                 }
@@ -155,11 +1031,33 @@ impl WebSocketServer {
         });
 
         // Spawn the handling of each connection in a separate task.
+        let connections = self.connections.clone();
         let messgae_handler = tokio::spawn(async move {
-            while let Ok((stream, addr)) = listener.accept().await {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    _ = token.cancelled() => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            // Such as too many open files, keep serving the current clients
+                            error!("Error accepting a connection: {}", e);
+                            sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    },
+                };
                 let broadcast_rx = broadcast_rx.clone();
-                tokio::spawn(async move {
-                    Self::handle_connection(stream, addr, broadcast_rx).await;
+                let state = state.clone();
+                let tls_acceptor = tls_acceptor.clone();
+                connections.spawn(async move {
+                    match tls_acceptor {
+                        Some(tls_acceptor) => match timeout(HANDSHAKE_TIMEOUT, tls_acceptor.accept(stream)).await {
+                            Ok(Ok(stream)) => Self::serve_http(stream, addr, broadcast_rx, state).await,
+                            Ok(Err(e)) => warn!("Error during the TLS handshake with {}: {}", addr, e),
+                            Err(_) => warn!("The TLS handshake with {} timed out", addr),
+                        },
+                        None => Self::serve_http(stream, addr, broadcast_rx, state).await,
+                    }
                 });
             }
         });
@@ -170,6 +1068,23 @@ impl WebSocketServer {
         Ok(())
     }
 
+    /// Write to the store on a blocking thread, SQLite waiting for the disk,
+    /// one write after the other so that the messages follow their battle
+    async fn store<F>(store: &Option<Arc<ChatStore>>, what: &str, write: F)
+    where
+        F: FnOnce(&ChatStore) -> Result<()> + Send + 'static,
+    {
+        let store = match store {
+            Some(store) => store.clone(),
+            None => return,
+        };
+        match tokio::task::spawn_blocking(move || write(&store)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Error storing the {}: {:?}", what, e),
+            Err(e) => warn!("Error storing the {}: {:?}", what, e),
+        }
+    }
+
     pub fn run(&self, token: CancellationToken) {
         // Start the websocket server
         let runtime = tokio::runtime::Builder::new_current_thread()
@@ -181,17 +1096,391 @@ impl WebSocketServer {
             tokio::select! {
                 // Using cloned token to listen to cancellation requests
                 _ = token.cancelled() => {}
-                _ = Self::server(
-                    self.ip.clone(),
-                    self.port,
-                    self.langeuage.clone(),
-                    self.access_key_id.clone(),
-                    self.access_key_secret.clone(),
-                    self.message_rx.clone()
-                ) => {}
+                result = self.server(token.clone()) => {
+                    if let Err(e) = result {
+                        error!("WebSocket server failed: {}", e);
+                    }
+                }
+            }
+
+            // Let the connections send their close frames
+            self.connections.close();
+            if timeout(CLOSE_TIMEOUT, self.connections.wait()).await.is_err() {
+                warn!("Some WebSocket connections did not close in time");
             }
         });
 
         info!("WebSocket server is stopped");
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::processor::{Player, Roster};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    fn chat_message(message: &str) -> ChatMessage {
+        ProcessedMessage::test(0, Some(1), message).message
+    }
+
+    async fn receive_ids<S>(stream: &mut S, count: usize) -> Vec<u64>
+    where
+        S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        let mut ids = Vec::new();
+        while ids.len() < count {
+            ids.push(receive_json(stream).await["id"].as_u64().unwrap());
+        }
+        ids
+    }
+
+    /// Start a server on a free port, returning its url and the sender of its messages
+    async fn start_server(history_size: usize) -> (String, async_channel::Sender<BattleEvent>) {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (tx, rx) = async_channel::bounded(8);
+        let server = WebSocketServer::new("127.0.0.1".to_string(), port, Interpreter::new(Language::ZH, None), rx)
+            .with_history_size(history_size);
+        tokio::spawn(async move { server.server(CancellationToken::new()).await });
+        listening(port).await;
+        (format!("ws://127.0.0.1:{}/", port), tx)
+    }
+
+    /// Wait for the server to accept connections on the port
+    async fn listening(port: u16) {
+        while tokio::net::TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    async fn receive_json<S>(stream: &mut S) -> serde_json::Value
+    where
+        S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        let msg = stream.next().await.unwrap().unwrap();
+        serde_json::from_str(msg.to_text().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn history_replayed_on_connect() {
+        let (url, tx) = start_server(2).await;
+        let (mut observer, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+
+        for text in ["gl hf", "cap B", "gg wp"] {
+            tx.send(BattleEvent::Chat(chat_message(text))).await.unwrap();
+        }
+        // Broadcast once in the history
+        assert_eq!(receive_ids(&mut observer, 3).await, vec![1, 2, 3]);
+
+        let (mut stream, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        assert_eq!(receive_ids(&mut stream, 2).await, vec![2, 3]);
+
+        tx.send(BattleEvent::Chat(chat_message("o7"))).await.unwrap();
+        assert_eq!(receive_ids(&mut stream, 1).await, vec![4]);
+
+        let (mut stream, _) = tokio_tungstenite::connect_async(format!("{}?since=3", url)).await.unwrap();
+        tx.send(BattleEvent::Chat(chat_message("push b pls"))).await.unwrap();
+        assert_eq!(receive_ids(&mut stream, 2).await, vec![4, 5]);
+    }
+
+    #[tokio::test]
+    async fn lifecycle_events() {
+        let (url, tx) = start_server(8).await;
+        let (mut observer, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        tx.send(BattleEvent::Started(None)).await.unwrap();
+        tx.send(BattleEvent::Chat(chat_message("gl hf"))).await.unwrap();
+        assert_eq!(receive_json(&mut observer).await["type"], "battle_started");
+        assert_eq!(receive_ids(&mut observer, 1).await, vec![1]);
+
+        // The start of the battle comes before its history
+        let (mut stream, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        assert_eq!(receive_json(&mut stream).await["type"], "battle_started");
+        assert_eq!(receive_json(&mut stream).await["type"], "chat");
+
+        tx.send(BattleEvent::Ended(BattleResult { game_id: Some(1), winning_team: Some(0) })).await.unwrap();
+        let ended = receive_json(&mut stream).await;
+        assert_eq!((&ended["type"], &ended["winning_team"]), (&serde_json::json!("battle_ended"), &serde_json::json!(0)));
+        tx.send(BattleEvent::Idle).await.unwrap();
+        assert_eq!(receive_json(&mut stream).await["type"], "idle");
+        tx.send(BattleEvent::ParseError("Data file not found".to_string())).await.unwrap();
+        assert_eq!(receive_json(&mut stream).await["message"], "Data file not found");
+
+        // No battle going on anymore, nor its history
+        let (mut stream, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        tx.send(BattleEvent::Chat(chat_message("o7"))).await.unwrap();
+        assert_eq!(receive_ids(&mut stream, 1).await, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn history_cleared_when_battle_starts() {
+        let (url, tx) = start_server(8).await;
+        let (mut stream, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        tx.send(BattleEvent::Started(None)).await.unwrap();
+        tx.send(BattleEvent::Chat(chat_message("gl hf"))).await.unwrap();
+        assert_eq!(receive_json(&mut stream).await["type"], "battle_started");
+        assert_eq!(receive_ids(&mut stream, 1).await, vec![1]);
+
+        // The next battle starts before its first message, in the same arena as far as known
+        tx.send(BattleEvent::Started(None)).await.unwrap();
+        assert_eq!(receive_json(&mut stream).await["type"], "battle_started");
+        let (mut stream, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        assert_eq!(receive_json(&mut stream).await["type"], "battle_started");
+        tx.send(BattleEvent::Chat(chat_message("o7"))).await.unwrap();
+        assert_eq!(receive_ids(&mut stream, 1).await, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn control_commands() {
+        let (url, tx) = start_server(8).await;
+        let (mut stream, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+
+        stream.send(Message::Text(r#"{"type": "ping", "request_id": 1}"#.to_string())).await.unwrap();
+        let reply = receive_json(&mut stream).await;
+        assert_eq!((reply["type"].as_str(), reply["request_id"].as_u64()), (Some("pong"), Some(1)));
+
+        stream.send(Message::Text(r#"{"type": "dance"}"#.to_string())).await.unwrap();
+        let reply = receive_json(&mut stream).await;
+        assert_eq!((reply["type"].as_str(), reply["code"].as_str()), (Some("error"), Some("unknown_command")));
+
+        stream.send(Message::Text(r#"{"type": "set_language", "language": "xx"}"#.to_string())).await.unwrap();
+        assert_eq!(receive_json(&mut stream).await["code"], "invalid_language");
+
+        stream.send(Message::Text(r#"{"type": "subscribe", "audiences": ["battle_team"]}"#.to_string())).await.unwrap();
+        assert_eq!(receive_json(&mut stream).await["audiences"], serde_json::json!(["battle_team"]));
+
+        let mut team_message = chat_message("cap B");
+        team_message.audience = "battle_team".to_string();
+        tx.send(BattleEvent::Chat(chat_message("gl hf"))).await.unwrap();
+        tx.send(BattleEvent::Chat(team_message)).await.unwrap();
+        assert_eq!(receive_ids(&mut stream, 1).await, vec![2]);
+
+        stream.send(Message::Text(r#"{"type": "history", "request_id": 2}"#.to_string())).await.unwrap();
+        assert_eq!(receive_ids(&mut stream, 1).await, vec![2]);
+        let reply = receive_json(&mut stream).await;
+        assert_eq!((reply["type"].as_str(), reply["count"].as_u64(), reply["last_id"].as_u64()), (Some("history"), Some(1), Some(2)));
+    }
+
+    #[tokio::test]
+    async fn filters() {
+        let (url, tx) = start_server(8).await;
+        let (mut team_stream, _) = tokio_tungstenite::connect_async(format!("{}?audience=battle_team", url)).await.unwrap();
+        let (mut stream, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+
+        stream.send(Message::Text(r#"{"type": "filter", "senders": ["Yusux"], "pattern": "(?i)cap"}"#.to_string())).await.unwrap();
+        let reply = receive_json(&mut stream).await;
+        assert_eq!(reply["type"], "filter");
+        assert_eq!(reply["senders"], serde_json::json!(["Yusux"]));
+        assert_eq!(reply["audiences"], serde_json::Value::Null);
+
+        stream.send(Message::Text(r#"{"type": "filter", "pattern": "("}"#.to_string())).await.unwrap();
+        assert_eq!(receive_json(&mut stream).await["code"], "invalid_filter");
+
+        let mut team_message = chat_message("CAP B");
+        team_message.audience = "battle_team".to_string();
+        let mut yusux_message = chat_message("cap A");
+        yusux_message.sender = "Yusux".to_string();
+        tx.send(BattleEvent::Chat(team_message)).await.unwrap();
+        tx.send(BattleEvent::Chat(yusux_message)).await.unwrap();
+        tx.send(BattleEvent::Chat(chat_message("gl hf"))).await.unwrap();
+        assert_eq!(receive_ids(&mut team_stream, 1).await, vec![1]);
+        assert_eq!(receive_ids(&mut stream, 1).await, vec![2]);
+
+        let response = tokio_tungstenite::connect_async(format!("{}?team=red", url)).await;
+        assert!(response.is_err());
+    }
+
+    #[tokio::test]
+    async fn tls() {
+        let dir = std::env::temp_dir().join(format!("chatrans-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        crate::server::generate_self_signed(vec!["localhost".to_string()], &cert, &key).unwrap();
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (tx, rx) = async_channel::bounded(8);
+        let server = WebSocketServer::new("127.0.0.1".to_string(), port, Interpreter::new(Language::ZH, None), rx)
+            .with_tls(crate::server::load_server_config(&cert, &key).unwrap());
+        tokio::spawn(async move { server.server(CancellationToken::new()).await });
+        listening(port).await;
+
+        // Trust the self-signed certificate
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(&cert).unwrap())) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let stream = connector.connect("localhost".try_into().unwrap(), stream).await.unwrap();
+        let (mut stream, _) = tokio_tungstenite::client_async(format!("wss://localhost:{}/", port), stream).await.unwrap();
+
+        tx.send(BattleEvent::Chat(chat_message("gl hf"))).await.unwrap();
+        assert_eq!(receive_ids(&mut stream, 1).await, vec![1]);
+
+        // Plain WebSocket is refused
+        assert!(tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/", port)).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn token_auth() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (_tx, rx) = async_channel::bounded(8);
+        let server = WebSocketServer::new("127.0.0.1".to_string(), port, Interpreter::new(Language::ZH, None), rx)
+            .with_auth(Authenticator::new().with_token("shared".to_string(), "s3cret".to_string()));
+        tokio::spawn(async move { server.server(CancellationToken::new()).await });
+        listening(port).await;
+
+        let url = format!("ws://127.0.0.1:{}/", port);
+        assert!(tokio_tungstenite::connect_async(&url).await.is_err());
+        assert!(tokio_tungstenite::connect_async(format!("{}?token=guess", url)).await.is_err());
+        assert!(tokio_tungstenite::connect_async(format!("{}?token=s3cret", url)).await.is_ok());
+
+        let mut request = url.into_client_request().unwrap();
+        request.headers_mut().insert("Authorization", "Bearer s3cret".parse().unwrap());
+        assert!(tokio_tungstenite::connect_async(request).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn close_frame_on_shutdown() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (_tx, rx) = async_channel::bounded(8);
+        let server = WebSocketServer::new("127.0.0.1".to_string(), port, Interpreter::new(Language::ZH, None), rx);
+        let token = CancellationToken::new();
+        tokio::spawn({
+            let token = token.clone();
+            async move { server.server(token).await }
+        });
+        listening(port).await;
+
+        // Neither a plain HTTP request nor a silent client bring the server down
+        let mut http = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut http, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let _silent = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+
+        let (mut stream, _) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/", port)).await.unwrap();
+        token.cancel();
+        match stream.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Away),
+            msg => panic!("Expected a close frame, got {:?}", msg),
+        }
+    }
+
+    async fn http_get(url: &str) -> reqwest::Response {
+        reqwest::Client::builder().no_proxy().build().unwrap().get(url).send().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn web_client() {
+        let (url, _tx) = start_server(8).await;
+        let http_url = url.replacen("ws://", "http://", 1);
+
+        let page = http_get(&http_url).await;
+        assert_eq!(page.status(), reqwest::StatusCode::OK);
+        assert!(page.text().await.unwrap().contains("app.js"));
+        assert_eq!(http_get(&format!("{}missing.html", http_url)).await.status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(http_get(&format!("{}ws", http_url)).await.status(), reqwest::StatusCode::UPGRADE_REQUIRED);
+
+        assert!(tokio_tungstenite::connect_async(format!("{}ws", url)).await.is_ok());
+    }
+
+    /// Backend prefixing the text with the target language
+    struct TaggingBackend;
+
+    #[async_trait::async_trait]
+    impl crate::interpreter::TranslationBackend for TaggingBackend {
+        fn name(&self) -> &str {
+            "tagging"
+        }
+
+        async fn translate(&self, text: &str, _source: Option<&Language>, target: &Language) -> Result<String, crate::interpreter::TranslateError> {
+            Ok(format!("[{}] {}", target, text))
+        }
+
+        fn supported_languages(&self) -> Vec<Language> {
+            Language::all()
+        }
+
+        async fn health_check(&self) -> Result<(), crate::interpreter::TranslateError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn default_language_kept_for_later() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (tx, rx) = async_channel::bounded(8);
+        let interpreter = Interpreter::new(Language::ZH, Some(Box::new(TaggingBackend)));
+        let server = WebSocketServer::new("127.0.0.1".to_string(), port, interpreter, rx);
+        tokio::spawn(async move { server.server(CancellationToken::new()).await });
+        listening(port).await;
+
+        // The only client reads English, the archive gets the default language too
+        let (mut observer, _) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/?lang=en", port)).await.unwrap();
+        tx.send(BattleEvent::Chat(chat_message("gl hf"))).await.unwrap();
+        assert_eq!(receive_json(&mut observer).await["translated"], "[en] gl hf");
+        let chat: serde_json::Value = http_get(&format!("http://127.0.0.1:{}/api/battles/1", port)).await.json().await.unwrap();
+        assert_eq!(chat["messages"][0]["translated"], "[zh] gl hf");
+    }
+
+    #[tokio::test]
+    async fn rest_api() {
+        let (url, tx) = start_server(8).await;
+        let (mut observer, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let api_url = format!("{}api", url.replacen("ws://", "http://", 1));
+
+        assert_eq!(http_get(&format!("{}/roster", api_url)).await.status(), reqwest::StatusCode::NOT_FOUND);
+        tx.send(BattleEvent::Roster(Roster {
+            game_id: Some(1),
+            players: vec![Player { name: "Rorin".to_string(), clan: String::new(), team: 0, ship_id: 409451 }],
+        })).await.unwrap();
+        for text in ["gl hf", "cap B"] {
+            tx.send(BattleEvent::Chat(chat_message(text))).await.unwrap();
+        }
+        // After the roster, in the archive once broadcast
+        assert_eq!(receive_ids(&mut observer, 2).await, vec![1, 2]);
+
+        let battles: serde_json::Value = http_get(&format!("{}/battles", api_url)).await.json().await.unwrap();
+        assert_eq!((battles[0]["game_id"].as_i64(), battles[0]["messages"].as_u64()), (Some(1), Some(2)));
+        let chat: serde_json::Value = http_get(&format!("{}/battles/1", api_url)).await.json().await.unwrap();
+        assert_eq!(chat["messages"][1]["original"], "cap B");
+        assert_eq!(chat["roster"][0]["name"], "Rorin");
+        let found: serde_json::Value = http_get(&format!("{}/search?player=rorin&text=CAP", api_url)).await.json().await.unwrap();
+        assert_eq!(found["messages"].as_array().unwrap().len(), 1);
+        let roster: serde_json::Value = http_get(&format!("{}/roster", api_url)).await.json().await.unwrap();
+        assert_eq!(roster["players"][0]["ship_id"], 409451);
+
+        assert_eq!(http_get(&format!("{}/battles/2", api_url)).await.status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(http_get(&format!("{}/search", api_url)).await.status(), reqwest::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn event_stream() {
+        let (url, tx) = start_server(8).await;
+        let (mut observer, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let events_url = format!("{}events", url.replacen("ws://", "http://", 1));
+        for text in ["gl hf", "cap B"] {
+            tx.send(BattleEvent::Chat(chat_message(text))).await.unwrap();
+        }
+        assert_eq!(receive_ids(&mut observer, 2).await, vec![1, 2]);
+
+        let mut response = reqwest::Client::builder().no_proxy().build().unwrap()
+            .get(&events_url)
+            .header("Last-Event-ID", "1")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        tx.send(BattleEvent::Chat(chat_message("gg wp"))).await.unwrap();
+
+        let mut received = String::new();
+        while !received.contains("id: 3\n") {
+            let chunk = response.chunk().await.unwrap().unwrap();
+            received.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        assert!(!received.contains("id: 1\n"));
+        assert!(received.starts_with("id: 2\nevent: chat\ndata: {"));
+    }
+}
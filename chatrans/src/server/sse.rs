@@ -0,0 +1,78 @@
+use futures_util::StreamExt;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::{
+    body::{Bytes, Frame},
+    header::{self, HeaderValue},
+    HeaderMap,
+    Response,
+};
+
+use crate::server::http::Body;
+
+/// An event of the stream, the data split into a `data` field per line,
+/// see https://html.spec.whatwg.org/multipage/server-sent-events.html.
+/// A line ends with CRLF, CR or LF alike for the clients, so a CR in the data cannot start another field.
+/// An event without id leaves the last event id of the client as is.
+pub fn event(id: Option<u64>, name: &str, data: &str) -> String {
+    let mut event = match id {
+        Some(id) => format!("id: {}\nevent: {}\n", id, name),
+        None => format!("event: {}\n", name),
+    };
+    for line in data.split("\r\n").flat_map(|line| line.split(['\r', '\n'])) {
+        event.push_str("data: ");
+        event.push_str(line);
+        event.push('\n');
+    }
+    event.push('\n');
+    event
+}
+
+/// A comment, ignored by the clients, keeping the connection alive
+pub fn comment(text: &str) -> String {
+    format!(": {}\n\n", text)
+}
+
+/// Id of the last event received, sent by the clients when reconnecting
+pub fn last_event_id(headers: &HeaderMap) -> Option<u64> {
+    headers.get("last-event-id")?.to_str().ok()?.trim().parse().ok()
+}
+
+/// Response streaming the events of the channel, ending once the channel is closed
+pub fn response(events: async_channel::Receiver<String>) -> Response<Body> {
+    let body = StreamBody::new(events.map(|event| Ok(Frame::data(Bytes::from(event)))));
+    let mut response = Response::new(body.boxed_unsync());
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    // Ask reverse proxies not to hold the events back
+    headers.insert("x-accel-buffering", HeaderValue::from_static("no"));
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format_events() {
+        assert_eq!(event(Some(42), "chat", r#"{"id":42}"#), "id: 42\nevent: chat\ndata: {\"id\":42}\n\n");
+        assert_eq!(event(Some(1), "chat", "gl\nhf"), "id: 1\nevent: chat\ndata: gl\ndata: hf\n\n");
+        // A CR alone ends a line too, not letting a message add fields
+        assert_eq!(
+            event(Some(2), "chat", "gl\r\nhf\revent: idle\rid: 99"),
+            "id: 2\nevent: chat\ndata: gl\ndata: hf\ndata: event: idle\ndata: id: 99\n\n",
+        );
+        assert_eq!(event(None, "idle", r#"{"type":"idle"}"#), "event: idle\ndata: {\"type\":\"idle\"}\n\n");
+        assert_eq!(comment("ping"), ": ping\n\n");
+    }
+
+    #[test]
+    fn parse_last_event_id() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(last_event_id(&headers), None);
+        headers.insert("Last-Event-ID", HeaderValue::from_static(" 42"));
+        assert_eq!(last_event_id(&headers), Some(42));
+        headers.insert("Last-Event-ID", HeaderValue::from_static("abc"));
+        assert_eq!(last_event_id(&headers), None);
+    }
+}
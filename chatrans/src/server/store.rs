@@ -0,0 +1,349 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::Mutex,
+};
+use anyhow::Result;
+use chrono::Utc;
+use rusqlite::{functions::FunctionFlags, params, Connection, OptionalExtension};
+use serde_derive::Serialize;
+use tracing::info;
+
+use crate::processor::{BattleMeta, Roster};
+use crate::server::server::ProcessedMessage;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS battles (
+    id INTEGER PRIMARY KEY,
+    -- Arena id, NULL until the map is loaded
+    game_id INTEGER,
+    -- When the server saw the battle first, RFC 3339
+    started_at TEXT NOT NULL,
+    map_name TEXT,
+    map_display_name TEXT,
+    game_type TEXT,
+    scenario TEXT,
+    date_time TEXT,
+    player_name TEXT,
+    player_vehicle TEXT,
+    client_version TEXT
+);
+CREATE INDEX IF NOT EXISTS battles_game_id ON battles (game_id);
+CREATE TABLE IF NOT EXISTS players (
+    battle_id INTEGER NOT NULL REFERENCES battles (id),
+    ship_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    clan TEXT NOT NULL,
+    team INTEGER NOT NULL,
+    PRIMARY KEY (battle_id, ship_id)
+);
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY,
+    battle_id INTEGER NOT NULL REFERENCES battles (id),
+    clock REAL NOT NULL,
+    sender TEXT NOT NULL,
+    -- The sender compared by the search, see `fold`
+    sender_folded TEXT NOT NULL,
+    clan TEXT NOT NULL,
+    team INTEGER NOT NULL,
+    ship_id INTEGER NOT NULL,
+    audience TEXT NOT NULL,
+    message TEXT NOT NULL,
+    -- Detected language, NULL if unknown
+    language TEXT
+);
+CREATE INDEX IF NOT EXISTS messages_sender ON messages (sender_folded);
+CREATE TABLE IF NOT EXISTS translations (
+    message_id INTEGER NOT NULL REFERENCES messages (id),
+    language TEXT NOT NULL,
+    translated TEXT NOT NULL,
+    PRIMARY KEY (message_id, language)
+);
+";
+
+/// The text compared ignoring the case, also as the `fold` function of the queries.
+/// `lower` and `NOCASE` only fold the ASCII letters, the names and chat are in any script.
+fn fold(text: &str) -> String {
+    text.to_lowercase()
+}
+
+/// A message read back from the database, with its battle
+#[derive(Debug, Serialize)]
+pub struct StoredMessage {
+    pub game_id: Option<i64>,
+    pub map: Option<String>,
+    pub game_type: Option<String>,
+    pub date_time: Option<String>,
+    pub clock: f64,
+    pub sender: String,
+    pub clan: Option<String>,
+    pub team: i64,
+    pub audience: String,
+    pub original: String,
+    pub language: Option<String>,
+    /// Translations done when the message arrived, by language
+    pub translations: BTreeMap<String, String>,
+}
+
+struct CurrentBattle {
+    id: i64,
+    game_id: Option<i64>,
+}
+
+/// Every battle seen and its chat, stored in a SQLite database
+pub struct ChatStore {
+    connection: Mutex<Connection>,
+    battle: Mutex<Option<CurrentBattle>>,
+}
+
+impl ChatStore {
+    /// Open the database at `path`, creating it if needed
+    pub fn open(path: &Path) -> Result<ChatStore> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        connection.create_scalar_function(
+            "fold",
+            1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+            |context| Ok(context.get::<Option<String>>(0)?.map(|text| fold(&text))),
+        )?;
+        info!("Chat history is stored in: {:?}", path);
+        Ok(ChatStore {
+            connection: Mutex::new(connection),
+            battle: Mutex::new(None),
+        })
+    }
+
+    /// Id of the row of the battle with the arena id, adding the battle if new.
+    /// A battle without arena id yet is taken as the beginning of the next one.
+    fn battle_id(&self, connection: &Connection, game_id: Option<i64>) -> Result<i64> {
+        let mut battle = self.battle.lock().unwrap();
+        if let Some(current) = battle.as_mut() {
+            if current.game_id == game_id {
+                return Ok(current.id);
+            }
+            if current.game_id.is_none() {
+                connection.execute("UPDATE battles SET game_id = ?1 WHERE id = ?2", params![game_id, current.id])?;
+                current.game_id = game_id;
+                return Ok(current.id);
+            }
+        }
+
+        // Such as when restarted during a battle
+        let existing = match game_id {
+            Some(game_id) => connection
+                .query_row(
+                    "SELECT id FROM battles WHERE game_id = ?1 ORDER BY id DESC LIMIT 1",
+                    params![game_id],
+                    |row| row.get(0),
+                )
+                .optional()?,
+            None => None,
+        };
+        let id = match existing {
+            Some(id) => id,
+            None => {
+                connection.execute(
+                    "INSERT INTO battles (game_id, started_at) VALUES (?1, ?2)",
+                    params![game_id, Utc::now().to_rfc3339()],
+                )?;
+                connection.last_insert_rowid()
+            }
+        };
+        *battle = Some(CurrentBattle { id, game_id });
+        Ok(id)
+    }
+
+    /// Record a new battle, which the next messages belong to
+    pub fn start_battle(&self, meta: &BattleMeta) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO battles (
+                started_at, map_name, map_display_name, game_type, scenario,
+                date_time, player_name, player_vehicle, client_version
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                Utc::now().to_rfc3339(),
+                meta.map_name,
+                meta.map_display_name,
+                meta.game_type,
+                meta.scenario,
+                meta.date_time,
+                meta.player_name,
+                meta.player_vehicle,
+                meta.client_version,
+            ],
+        )?;
+        *self.battle.lock().unwrap() = Some(CurrentBattle {
+            id: connection.last_insert_rowid(),
+            game_id: None,
+        });
+        Ok(())
+    }
+
+    pub fn set_roster(&self, roster: &Roster) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let battle_id = self.battle_id(&connection, roster.game_id)?;
+        let txn = connection.transaction()?;
+        for player in &roster.players {
+            txn.execute(
+                "INSERT OR REPLACE INTO players (battle_id, ship_id, name, clan, team) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![battle_id, player.ship_id, player.name, player.clan, player.team],
+            )?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Store the message and the translations done so far
+    pub fn insert(&self, processed: &ProcessedMessage) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let message = &processed.message;
+        let battle_id = self.battle_id(&connection, message.game_id)?;
+        let txn = connection.transaction()?;
+        txn.execute(
+            "INSERT INTO messages (battle_id, clock, sender, sender_folded, clan, team, ship_id, audience, message, language)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                battle_id,
+                message.clock,
+                message.sender,
+                fold(&message.sender),
+                message.clan,
+                message.team,
+                message.ship_id,
+                message.audience,
+                message.message,
+                message.language.map(|language| language.code()),
+            ],
+        )?;
+        let message_id = txn.last_insert_rowid();
+        for (language, translated) in &processed.translations {
+            if let Some(translated) = translated {
+                txn.execute(
+                    "INSERT INTO translations (message_id, language, translated) VALUES (?1, ?2, ?3)",
+                    params![message_id, language.code(), translated],
+                )?;
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// The last `limit` messages sent by the player and containing the text,
+    /// across all the battles, both compared ignoring the case
+    pub fn search(&self, player: Option<&str>, text: Option<&str>, limit: usize) -> Result<Vec<StoredMessage>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT m.id, b.game_id, b.map_display_name, b.game_type, b.date_time,
+                m.clock, m.sender, m.clan, m.team, m.audience, m.message, m.language
+            FROM messages m JOIN battles b ON b.id = m.battle_id
+            WHERE (?1 IS NULL OR m.sender_folded = ?1)
+                AND (?2 IS NULL OR instr(fold(m.message), ?2) > 0
+                    OR EXISTS (SELECT 1 FROM translations t
+                        WHERE t.message_id = m.id AND instr(fold(t.translated), ?2) > 0))
+            ORDER BY m.id DESC
+            LIMIT ?3",
+        )?;
+        let rows = statement.query_map(params![player.map(fold), text.map(fold), limit as i64], |row| {
+            let clan: String = row.get(7)?;
+            Ok((row.get::<_, i64>(0)?, StoredMessage {
+                game_id: row.get(1)?,
+                map: row.get(2)?,
+                game_type: row.get(3)?,
+                date_time: row.get(4)?,
+                clock: row.get(5)?,
+                sender: row.get(6)?,
+                clan: Some(clan).filter(|clan| !clan.is_empty()),
+                team: row.get(8)?,
+                audience: row.get(9)?,
+                original: row.get(10)?,
+                language: row.get(11)?,
+                translations: BTreeMap::new(),
+            }))
+        })?;
+
+        let mut translations = connection.prepare("SELECT language, translated FROM translations WHERE message_id = ?1")?;
+        let mut messages = Vec::new();
+        for row in rows {
+            let (id, mut message) = row?;
+            message.translations = translations
+                .query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?;
+            messages.push(message);
+        }
+        messages.reverse();
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use super::*;
+    use crate::interpreter::Language;
+    use crate::processor::Player;
+
+    fn message(game_id: Option<i64>, sender: &str, text: &str) -> ProcessedMessage {
+        let mut processed = ProcessedMessage::test(1, game_id, text);
+        processed.message.sender = sender.to_string();
+        processed.message.language = Some(Language::EN);
+        processed.translations = HashMap::from([(Language::ZH, Some(format!("译: {}", text))), (Language::RU, None)]);
+        processed
+    }
+
+    fn meta(map: &str) -> BattleMeta {
+        BattleMeta {
+            map_name: "spaces/16_OC_bees_to_honey".to_string(),
+            map_display_name: map.to_string(),
+            game_type: "RandomBattle".to_string(),
+            scenario: "Domination".to_string(),
+            date_time: "18.10.2026 12:00:00".to_string(),
+            player_name: "Rorin".to_string(),
+            player_vehicle: "PASC020-Des-Moines-1948".to_string(),
+            client_version: "25,10,0,0".to_string(),
+            players: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn store_and_search() {
+        let path = std::env::temp_dir().join(format!("chatrans-store-{}.sqlite", uuid::Uuid::new_v4()));
+        {
+            let store = ChatStore::open(&path).unwrap();
+            store.start_battle(&meta("Trap")).unwrap();
+            store.set_roster(&Roster {
+                game_id: None,
+                players: vec![Player { name: "Rorin".to_string(), clan: String::new(), team: 0, ship_id: 409451 }],
+            }).unwrap();
+            store.insert(&message(Some(1), "Rorin", "cap B")).unwrap();
+            store.start_battle(&meta("Islands of Ice")).unwrap();
+            store.insert(&message(Some(2), "Yusux", "gl hf")).unwrap();
+        }
+
+        // Survives restarts, and a message of a known battle joins it
+        let store = ChatStore::open(&path).unwrap();
+        store.insert(&message(Some(1), "rorin", "gg wp")).unwrap();
+
+        let found = store.search(Some("RORIN"), None, 10).unwrap();
+        assert_eq!(found.iter().map(|message| message.original.as_str()).collect::<Vec<_>>(), vec!["cap B", "gg wp"]);
+        assert_eq!(found[0].map.as_deref(), Some("Trap"));
+        assert_eq!(found[1].game_id, Some(1));
+        assert_eq!(found[0].translations, BTreeMap::from([("zh".to_string(), "译: cap B".to_string())]));
+
+        let found = store.search(None, Some("译: GL"), 10).unwrap();
+        assert_eq!((found.len(), found[0].map.as_deref()), (1, Some("Islands of Ice")));
+        // Not only the ASCII letters
+        store.insert(&message(Some(2), "Ёжик", "ПРИВЕТ всем")).unwrap();
+        let found = store.search(Some("ёЖИК"), Some("привет"), 10).unwrap();
+        assert_eq!(found.iter().map(|message| message.original.as_str()).collect::<Vec<_>>(), vec!["ПРИВЕТ всем"]);
+        assert_eq!(store.search(Some("Rorin"), None, 1).unwrap()[0].original, "gg wp");
+
+        // The sender is looked up in the index rather than folded row by row
+        let plan: String = store.connection.lock().unwrap()
+            .query_row("EXPLAIN QUERY PLAN SELECT id FROM messages WHERE sender_folded = ?1", params!["rorin"], |row| row.get(3))
+            .unwrap();
+        assert!(plan.contains("messages_sender"), "{}", plan);
+        std::fs::remove_file(path).unwrap();
+    }
+}
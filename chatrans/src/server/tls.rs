@@ -0,0 +1,73 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, Write},
+    path::Path,
+    sync::Arc,
+};
+use anyhow::{anyhow, Result};
+use tokio_rustls::rustls::ServerConfig;
+use tracing::info;
+
+/// Load the TLS configuration from a PEM certificate chain and private key
+pub fn load_server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {:?}", cert));
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+        .ok_or_else(|| anyhow!("No private key found in {:?}", key))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+/// Generate a self-signed certificate valid for the host names or ips,
+/// and save it with its private key as PEM files
+pub fn generate_self_signed(hosts: Vec<String>, cert: &Path, key: &Path) -> Result<()> {
+    info!("Generating a self-signed certificate for: {}", hosts.join(", "));
+    let certified = rcgen::generate_simple_self_signed(hosts)?;
+    fs::write(cert, certified.cert.pem())?;
+    write_private(key, certified.key_pair.serialize_pem().as_bytes())?;
+    info!("Self-signed certificate is saved to: {:?}, private key to: {:?}", cert, key);
+    Ok(())
+}
+
+/// Write a file only the user can read, whatever the umask
+fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // The mode only applies to a new file
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(contents)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn self_signed_round_trip() {
+        let dir = std::env::temp_dir().join(format!("chatrans-tls-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+
+        generate_self_signed(vec!["localhost".to_string(), "127.0.0.1".to_string()], &cert, &key).unwrap();
+        assert!(load_server_config(&cert, &key).is_ok());
+        // The certificate is not a private key
+        assert!(load_server_config(&cert, &cert).is_err());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&key).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
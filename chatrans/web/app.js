@@ -0,0 +1,171 @@
+// Chat viewer of the chatrans server, see README for the parameters of the page
+(function () {
+    "use strict";
+
+    const params = new URLSearchParams(location.search);
+    const overlay = params.has("overlay") && params.get("overlay") !== "0";
+    // Seconds before a message fades out in overlay mode, 0 keeps them
+    const fade = Number(params.get("fade") || (overlay ? 30 : 0));
+    const maxMessages = Number(params.get("max") || (overlay ? 20 : 1000));
+    // Forwarded to the server as is
+    const forwarded = ["lang", "token", "audience", "team", "sender", "pattern"];
+
+    const messages = document.getElementById("messages");
+    const status = document.getElementById("status");
+    const languageInput = document.getElementById("language");
+    const originalInput = document.getElementById("original");
+
+    let socket = null;
+    let lastId = 0;
+    // Start time of the battle shown, its start is sent again on reconnect
+    let battleStart = null;
+    let retryDelay = 1000;
+
+    if (overlay) {
+        document.body.classList.add("overlay");
+    }
+    languageInput.value = params.get("lang") || "";
+    if (params.get("original") === "0") {
+        originalInput.checked = false;
+    }
+    document.body.classList.toggle("hide-original", !originalInput.checked);
+
+    function socketUrl() {
+        const query = new URLSearchParams();
+        for (const name of forwarded) {
+            if (params.has(name)) {
+                query.set(name, params.get(name));
+            }
+        }
+        query.set("format", "json");
+        if (lastId > 0) {
+            query.set("since", lastId);
+        }
+        const scheme = location.protocol === "https:" ? "wss:" : "ws:";
+        return `${scheme}//${location.host}/ws?${query}`;
+    }
+
+    function element(tag, className, text) {
+        const node = document.createElement(tag);
+        node.className = className;
+        if (text !== undefined) {
+            node.textContent = text;
+        }
+        return node;
+    }
+
+    function append(node) {
+        const atBottom = window.innerHeight + window.scrollY >= document.body.scrollHeight - 10;
+        messages.appendChild(node);
+        while (messages.childElementCount > maxMessages) {
+            messages.firstElementChild.remove();
+        }
+        if (fade > 0) {
+            setTimeout(() => node.classList.add("faded"), fade * 1000);
+            setTimeout(() => node.remove(), fade * 1000 + 1000);
+        }
+        if (atBottom) {
+            window.scrollTo(0, document.body.scrollHeight);
+        }
+    }
+
+    function showChat(chat) {
+        lastId = Math.max(lastId, chat.id);
+        const node = element("div", `message team-${chat.team}`);
+        const minutes = Math.floor(chat.clock / 60);
+        const seconds = Math.floor(chat.clock % 60).toString().padStart(2, "0");
+        node.appendChild(element("span", "clock", `${minutes}:${seconds}`));
+        if (chat.clan) {
+            node.appendChild(element("span", "clan", `[${chat.clan}]`));
+        }
+        node.appendChild(element("span", "sender", chat.sender));
+        node.appendChild(element("span", "audience", chat.audience.replace("battle_", "")));
+        const original = element("span", "original", chat.original);
+        node.appendChild(original);
+        if (chat.translated) {
+            original.classList.add("has-translation");
+            node.appendChild(element("span", "translated", chat.translated));
+        }
+        append(node);
+    }
+
+    function showBattleStarted(battle) {
+        if (battle.date_time && battle.date_time === battleStart) {
+            return;
+        }
+        battleStart = battle.date_time || null;
+        messages.replaceChildren();
+        const title = battle.map_display_name
+            ? `${battle.map_display_name} · ${battle.game_type}`
+            : "New battle";
+        append(element("div", "message battle started", title));
+    }
+
+    function showBattleEnded(result) {
+        const text = result.winning_team === null
+            ? "Battle over"
+            : `Battle over, team ${result.winning_team} won`;
+        append(element("div", "message battle", text));
+    }
+
+    function setStatus(connected) {
+        status.textContent = connected ? "Connected" : "Disconnected";
+        status.className = connected ? "connected" : "disconnected";
+    }
+
+    function connect() {
+        socket = new WebSocket(socketUrl());
+        socket.onopen = () => {
+            retryDelay = 1000;
+            setStatus(true);
+        };
+        socket.onmessage = (event) => {
+            const message = JSON.parse(event.data);
+            switch (message.type) {
+                case "chat":
+                    showChat(message);
+                    break;
+                case "battle_started":
+                    showBattleStarted(message);
+                    break;
+                case "battle_ended":
+                    showBattleEnded(message);
+                    break;
+                case "idle":
+                    battleStart = null;
+                    break;
+                case "error":
+                case "parse_error":
+                    append(element("div", "message error", message.message));
+                    break;
+            }
+        };
+        socket.onclose = () => {
+            setStatus(false);
+            // Reconnect, resuming after the last message received
+            setTimeout(connect, retryDelay);
+            retryDelay = Math.min(retryDelay * 2, 30000);
+        };
+    }
+
+    languageInput.addEventListener("change", () => {
+        const language = languageInput.value.trim();
+        if (language) {
+            params.set("lang", language);
+        } else {
+            params.delete("lang");
+        }
+        history.replaceState(null, "", `?${params}`);
+        if (language && socket && socket.readyState === WebSocket.OPEN) {
+            socket.send(JSON.stringify({ type: "set_language", language: language }));
+        }
+    });
+    originalInput.addEventListener("change", () => {
+        document.body.classList.toggle("hide-original", !originalInput.checked);
+    });
+    document.getElementById("clear").addEventListener("click", () => {
+        messages.replaceChildren();
+    });
+
+    connect();
+})();
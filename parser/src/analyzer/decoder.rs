@@ -374,7 +374,8 @@ pub enum DecodedPacketPayload<'replay, 'argtype, 'rawpacket> {
     PropertyUpdate(&'rawpacket crate::packet2::PropertyUpdatePacket<'argtype>),
     /// Indicates that the battle has ended
     BattleEnd {
-        /// The team ID of the winning team (corresponds to the teamid in [OnArenaStateReceivedPlayer])
+        /// The team ID of the winning team (corresponds to the teamid in [OnArenaStateReceivedPlayer]),
+        /// -1 for a draw or if the replay does not tell
         winning_team: i8,
         /// Unknown
         // TODO: Probably how the game was won? (time expired, score, or ships destroyed)
@@ -781,6 +782,20 @@ where
                 arg2,
                 players: players_out,
             }
+        } else if *method == "onBattleEnd" {
+            // Older versions tell the winner, newer ones nothing
+            let winning_team = match args.first() {
+                Some(crate::rpc::typedefs::ArgValue::Int8(team)) => *team,
+                _ => -1,
+            };
+            let unknown = match args.get(1) {
+                Some(crate::rpc::typedefs::ArgValue::Uint8(unknown)) => *unknown,
+                _ => 0,
+            };
+            DecodedPacketPayload::BattleEnd {
+                winning_team,
+                unknown,
+            }
         } else {
             DecodedPacketPayload::EntityMethod(packet)
         }
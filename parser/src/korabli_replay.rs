@@ -18,6 +18,18 @@ const BLOWFISH_KEY: [u8; 16] = [
     0xFA, 0x98, 0xEC, 0x4E, 0x13, 0x19, 0x79, 0xFB,
 ];
 
+/// A player of the battle as listed in the metadata
+#[allow(non_snake_case)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VehicleInfoMeta {
+    /// Game parameters id of the ship
+    pub shipId: u64,
+    /// 0 for the player, 1 for an ally, 2 for an enemy
+    pub relation: u32,
+    pub id: i64,
+    pub name: String,
+}
+
 #[allow(non_snake_case)]
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ReplayMeta {
@@ -52,6 +64,8 @@ pub struct ReplayMeta {
     pub disabledShipClasses: Vec<String>,
     pub playerVehicle: String,
     pub battleDuration: u32,
+    #[serde(default)]
+    pub vehicles: Vec<VehicleInfoMeta>,
 }
 
 fn decode_meta(meta: &[u8]) -> Result<ReplayMeta, Error> {